@@ -5,9 +5,12 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use anyhow::Context;
+use calamine::{DataType, Range, Reader};
 use erreport::path::ErrorPaths;
 
 use crate::{
+    expr::{Condition, Expand},
     table::{self, Table},
     template::{ContextIndex, InheritedContext},
     Record,
@@ -37,94 +40,200 @@ impl<'a> Definition {
         }
     }
 
+    /// The records selected by `index`, in order.
+    ///
+    /// Fails if a table doesn't exist, or for a [`ContextIndex::GroupBy`] whose groups come from
+    /// [`groups`](Definition::groups) instead. Each record is an error instead if a where clause can't be evaluated for it,
+    /// or it has no `$id` in an other loop.
     pub fn index(
         &'a self,
         index: &'a ContextIndex,
         record: &'a Record,
         ctx: &'a InheritedContext,
-    ) -> Option<Box<dyn Iterator<Item = &'a Record> + 'a>> {
+    ) -> anyhow::Result<Records<'a>> {
         match index {
-            ContextIndex::ValueList(_) => None,
-            ContextIndex::Table { table_name } => match table_name.as_str() {
-                "vars" => Some(Box::new(self.vars.iter())),
-                def => match self.defs.get(def) {
-                    Some(t) => Some(Box::new(t.iter())),
-                    None => None,
-                },
-            },
+            ContextIndex::ValueList(records) => Ok(Box::new(records.iter().map(anyhow::Ok))),
+            ContextIndex::Table { table_name } => {
+                Ok(Box::new(self.table(table_name)?.iter().map(anyhow::Ok)))
+            }
             ContextIndex::FilteredTableWhere {
                 table_name,
                 where_clause,
-            } => match table_name.as_str() {
-                "vars" => Some(Box::new(self.vars.iter().filter(move |r| {
-                    where_clause
-                        .matches(r, &self.defs, ctx)
-                        .unwrap_or_else(|_| panic!("Invalid match when evaluating where clause {where_clause:?} for record r {r:?} and table `{}`", table_name.as_str()))
-                }))),
-                def => match self.defs.get(def) {
-                    Some(t) => Some(Box::new(t.iter().filter(move |r| {
-                        where_clause
-                            .matches(r, &self.defs, ctx)
-                            .unwrap_or_else(|e| panic!("Invalid match error: {e:?} when evaluating where clause {where_clause:?} for record r {r:?} and table `{}`", table_name.as_str()))
-                    }))),
-                    None => None
-                },
-            },
-            ContextIndex::FilteredTableOther {
+            } => self.filtered(table_name, Some(where_clause), None, record, ctx),
+            ContextIndex::FilteredTableOther { table_name, index } => {
+                let other = self.other(table_name, index.as_ref(), record)?;
+                self.filtered(table_name, None, Some(other), record, ctx)
+            }
+            ContextIndex::FilteredTableOtherWhere {
                 table_name,
+                where_clause,
                 index,
             } => {
-                let other_index = index.as_ref().unwrap_or(table_name);
-                let this_value = record.get(other_index)?;
-                match table_name.as_str() {
-                    "vars" => {
-                        Some(Box::new(self.vars.iter().filter(move |r| {
-                            r.get("$id").map(|v| v != this_value)
-                            .unwrap_or_else(|| panic!("Missing $id field when indexing `{other_index}` while evaluating other clause for record {r:?}"))
-                        })))
-                    }
-                    def => match self.defs.get(def) {
-                        Some(t) => Some(Box::new(t.iter().filter(move |r| {
-                            r.get("$id").map(|v| v != this_value)
-                            .unwrap_or_else(|| panic!("Missing $id field when indexing `{other_index}` while evaluating other clause for record {r:?}"))
-                        }))),
-                        None => None
-                    },
-                }
-            },
-            ContextIndex::FilteredTableOtherWhere { table_name, where_clause, index } => {
-                let other_index = index.as_ref().unwrap_or(table_name);
-                let this_value = record.get(other_index)?;
-                match table_name.as_str() {
-                    "vars" => {
-                        Some(Box::new(self.vars.iter().filter(move |r| {
-                            r.get("$id").map(|v| v != this_value)
-                            .unwrap_or_else(|| panic!("Missing $id field when indexing `{other_index}` while evaluating other clause for record {r:?}"))
-                            && where_clause
-                                .matches(r, &self.defs, ctx)
-                                .unwrap_or_else(|_| panic!("Invalid match when evaluating where clause {where_clause:?} for record r {r:?} and table `{}`", table_name.as_str()))
-                        })))
+                let other = self.other(table_name, index.as_ref(), record)?;
+                self.filtered(table_name, Some(where_clause), Some(other), record, ctx)
+            }
+            ContextIndex::GroupRecords { context_name } => {
+                let records = ctx.records(context_name).ok_or_else(|| {
+                    anyhow::anyhow!("`{context_name}` is not the context of a group by loop, so it has no records")
+                })?;
+                Ok(Box::new(records.iter().map(anyhow::Ok)))
+            }
+            ContextIndex::GroupBy { key, .. } => anyhow::bail!(
+                "Records grouped by `{key:?}` are indexed as groups, see `Definition::groups`"
+            ),
+            ContextIndex::Ordered { index, order } => {
+                let records = self
+                    .index(index, record, ctx)?
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let table = index
+                    .table_name()
+                    .and_then(|table_name| self.get(table_name));
+                let ordered = order
+                    .apply(records.into_iter(), table, &self.defs, ctx)
+                    .with_context(|| format!("Failed to order records by {order:?}"))?;
+                Ok(Box::new(ordered.into_iter().map(anyhow::Ok)))
+            }
+        }
+    }
+
+    fn table(&'a self, table_name: &str) -> anyhow::Result<&'a Table> {
+        self.get(table_name)
+            .ok_or_else(|| anyhow::anyhow!("There is no table named `{table_name}`"))
+    }
+
+    /// The value of the current record that an other loop leaves out of `table_name`
+    fn other(
+        &'a self,
+        table_name: &'a str,
+        index: Option<&'a String>,
+        record: &'a Record,
+    ) -> anyhow::Result<&'a str> {
+        let other_index = index.map_or(table_name, String::as_str);
+        let value = record.get(other_index).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Failed to find the other records of `{table_name}`: the current record has no field `{other_index}`"
+            )
+        })?;
+
+        Ok(value)
+    }
+
+    /// The records of `table_name` that match `where_clause` and, in other loops, whose `$id` isn't `other`
+    ///
+    /// Errors name the row of the record they are for, the header is row 1
+    fn filtered(
+        &'a self,
+        table_name: &'a str,
+        where_clause: Option<&'a Condition>,
+        other: Option<&'a str>,
+        record: &'a Record,
+        ctx: &'a InheritedContext,
+    ) -> anyhow::Result<Records<'a>> {
+        let table = self.table(table_name)?;
+
+        let records = table.iter().enumerate().filter_map(move |(n, r)| {
+            if let Some(other) = other {
+                match r.get("$id") {
+                    Some(id) if id == other => return None,
+                    Some(_) => {}
+                    None => {
+                        return Some(Err(anyhow::anyhow!(
+                            "Row {} of `{table_name}` has no `$id`, which other loops need to leave out the current record",
+                            table.row(n)
+                        )))
                     }
-                    def => match self.defs.get(def) {
-                        Some(t) => Some(Box::new(t.iter().filter(move |r| {
-                            r.get("$id").map(|v| v != this_value)
-                            .unwrap_or_else(|| panic!("Missing $id field when indexing `{other_index}` while evaluating other clause for record {r:?}"))
-                            && where_clause
-                                .matches(r, &self.defs, ctx)
-                                .unwrap_or_else(|_| panic!("Invalid match when evaluating where clause {where_clause:?} for record r {r:?} and table `{}`", table_name.as_str()))
-                        }))),
-                        None => None
-                    },
                 }
-            },
+            }
+
+            let Some(where_clause) = where_clause else {
+                return Some(Ok(r));
+            };
+            match where_clause.matches(r, Some(table), record, &self.defs, ctx) {
+                Ok(true) => Some(Ok(r)),
+                Ok(false) => None,
+                Err(e) => Some(Err(e.context(format!(
+                    "Failed to evaluate where clause `{where_clause}` for row {} of `{table_name}`",
+                    table.row(n)
+                )))),
+            }
+        });
+
+        Ok(Box::new(records))
+    }
+}
+
+/// The records selected by a [`ContextIndex`], see [`Definition::index`]
+pub type Records<'a> = Box<dyn Iterator<Item = anyhow::Result<&'a Record>> + 'a>;
+
+impl<'a> Definition {
+    /// Groups the records selected by `index` by the value of `key` in each record.
+    ///
+    /// Groups are in the order of their first record, records keep their order within each group
+    pub fn groups(
+        &'a self,
+        index: &'a ContextIndex,
+        key: &Expand,
+        record: &'a Record,
+        ctx: &'a InheritedContext,
+    ) -> anyhow::Result<Vec<(String, Vec<&'a Record>)>> {
+        let records = self
+            .index(index, record, ctx)
+            .with_context(|| format!("Failed to index records to group by `{key:?}`"))?;
+
+        let mut groups: Vec<(String, Vec<&'a Record>)> = Vec::new();
+        for grouped in records {
+            let grouped = grouped?;
+            let value = key.run(grouped, &self.defs, ctx).with_context(|| {
+                format!("Failed expansion during group by: `{key:?}` for record {grouped:?}")
+            })?;
+
+            match groups.iter_mut().find(|(group, _)| group == &value) {
+                Some((_, members)) => members.push(grouped),
+                None => groups.push((value, vec![grouped])),
+            }
         }
+
+        Ok(groups)
     }
 }
 
 impl Definition {
-    pub fn from_ods_file<P: AsRef<Path>>(path: P) -> Self {
-        let _ = path;
-        todo!("read definitions from ods file")
+    /// Reads every sheet of a workbook, the format is detected from the file extension:
+    /// `.xlsx`, `.xlsm`, `.xlsb`, `.xls` or `.ods`.
+    ///
+    /// The sheet named `$vars` becomes [`vars`](Definition::vars), every other sheet becomes a def named after the sheet.
+    ///
+    /// See [`cell_value`](crate::table::cell_value) for how each cell becomes a [`Value`](crate::table::Value).
+    pub fn from_workbook<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut workbook = calamine::open_workbook_auto(path)
+            .with_context(|| format!("Failed to open workbook `{}`", path.display()))?;
+
+        Definition::from_worksheets(workbook.worksheets())
+            .with_context(|| format!("Failed to read workbook `{}`", path.display()))
+    }
+
+    /// Reads every sheet of an `.ods` workbook, see [`Definition::from_workbook`]
+    pub fn from_ods_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Definition::from_workbook(path)
+    }
+
+    fn from_worksheets(worksheets: Vec<(String, Range<DataType>)>) -> anyhow::Result<Self> {
+        let mut vars = None;
+        let mut defs = HashMap::new();
+
+        for (name, range) in worksheets {
+            if name == "$vars" {
+                vars = Some(table::from_range("vars".to_owned(), &range)?);
+            } else {
+                let type_ = table::from_range(name.clone(), &range)?;
+                defs.insert(name, type_);
+            }
+        }
+
+        let vars = vars.ok_or_else(|| anyhow::anyhow!("workbook has no `$vars` sheet"))?;
+
+        Ok(Definition { vars, defs })
     }
 
     /// vars is required, defs may be empty. Strings are expected to be in csv format.
@@ -185,3 +294,98 @@ impl Definition {
         Ok(definition)
     }
 }
+
+impl Definition {
+    /// Checks the definitions for problems that would otherwise only show up halfway through populating a template:
+    ///
+    /// * every def has a `$id` field, whose values are not empty and unique
+    /// * a field named after a def refers to that def, so each of its values must be the `$id` of one of its records
+    ///
+    /// Every problem is reported together in the error, rather than only the first.
+    /// Rows are numbered as in the file or sheet they were read from, the header is row 1.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+
+        let mut defs: Vec<&Table> = self.defs.values().collect();
+        defs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for def in &defs {
+            validate_ids(def, &mut problems);
+        }
+
+        for table in std::iter::once(&self.vars).chain(defs) {
+            self.validate_references(table, &mut problems);
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let problems: Vec<String> = problems
+            .iter()
+            .map(|problem| format!("- {problem}"))
+            .collect();
+        anyhow::bail!(
+            "Found {} problems in the definitions:\n{}",
+            problems.len(),
+            problems.join("\n")
+        )
+    }
+
+    fn validate_references(&self, table: &Table, problems: &mut Vec<String>) {
+        for field in &table.fields {
+            let Some(def) = self.defs.get(field) else {
+                continue;
+            };
+            // a def without a `$id` field has already been reported
+            if !def.has_field("$id") {
+                continue;
+            }
+
+            for (n, record) in table.iter().enumerate() {
+                let Some(value) = record.get(field).filter(|value| !value.is_empty()) else {
+                    continue;
+                };
+                if !def.iter().any(|r| r.get("$id") == Some(value)) {
+                    problems.push(format!(
+                        "`{}` row {} field `{field}`: `{value}` is not the `$id` of any record in `{}`",
+                        table.name,
+                        table.row(n),
+                        def.name
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn validate_ids(def: &Table, problems: &mut Vec<String>) {
+    if !def.has_field("$id") {
+        problems.push(format!("`{}` has no `$id` field", def.name));
+        return;
+    }
+
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for (n, record) in def.iter().enumerate() {
+        let id = record.get("$id").map(String::as_str).unwrap_or_default();
+        if id.trim().is_empty() {
+            problems.push(format!(
+                "`{}` row {} has an empty `$id`",
+                def.name,
+                def.row(n)
+            ));
+            continue;
+        }
+
+        if let Some(first) = seen.get(id) {
+            problems.push(format!(
+                "`{}` row {} has the same `$id` `{id}` as row {}",
+                def.name,
+                def.row(n),
+                def.row(*first)
+            ));
+        } else {
+            seen.insert(id, n);
+        }
+    }
+}
@@ -1,14 +1,19 @@
 use anyhow::{anyhow, Context as _};
 use parsely::{result_ext::*, Parse};
-use std::{collections::HashMap, str::FromStr};
+use std::{cmp::Ordering, collections::HashMap, fmt, str::FromStr};
 
+mod filter;
 mod parsing;
-pub use parsing::template;
+pub use filter::{Filter, FilterFn, Filters, BUILTIN_FILTERS};
 use parsing::{expr, for_tag};
+pub use parsing::{template, TemplateSyntax};
+
+mod check;
+pub(crate) use check::check;
 
 use crate::{
     template::{ContextIndex, InheritedContext},
-    Record, Table,
+    ColumnType, Definition, Record, Table,
 };
 
 /// [`Expr`] is exactly what is contained within `{{ }}` braces.
@@ -17,8 +22,267 @@ use crate::{
 ///
 /// Each [`BlockExpr`] opens a [`Block`] which closes with a corresponding `{@ end ___ @} braces`
 #[derive(Clone, Debug, PartialEq)]
-pub enum Expr {
+pub struct Expr {
+    pub kind: ExprKind,
+
+    /// The `{{ }}` tag the expression was parsed from
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind) -> Self {
+        Expr {
+            kind,
+            span: Span::default(),
+        }
+    }
+}
+
+/// The kinds of [`Expr`], which are also the expressions an [`AssignedValue`] can be
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprKind {
     Expand(Expand),
+    Filtered(Filtered),
+    Aggregate(Aggregate),
+}
+
+impl ExprKind {
+    pub fn run(
+        &self,
+        record: &Record,
+        def: &Definition,
+        context: &InheritedContext,
+        filters: &Filters,
+    ) -> anyhow::Result<String> {
+        match self {
+            ExprKind::Expand(expand) => expand.run_current(record, &def.defs, context),
+            ExprKind::Filtered(filtered) => filtered.run(record, &def.defs, context, filters),
+            ExprKind::Aggregate(aggregate) => aggregate.run(record, def, context, filters),
+        }
+    }
+
+    /// The [`Filter`]s applied to the value of this expression, in order
+    pub fn filters(&self) -> &[Filter] {
+        match self {
+            ExprKind::Expand(_) => &[],
+            ExprKind::Filtered(filtered) => &filtered.filters,
+            ExprKind::Aggregate(aggregate) => &aggregate.filters,
+        }
+    }
+}
+
+/// An [`Expand`] whose value is passed through one or more [`Filter`]s, in order
+///
+/// ```bash
+/// {{country.possessive | upper | pad(10)}}
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filtered {
+    pub expand: Expand,
+    pub filters: Vec<Filter>,
+}
+
+impl Filtered {
+    pub fn run(
+        &self,
+        record: &Record,
+        defs: &HashMap<String, Table>,
+        context: &InheritedContext,
+        filters: &Filters,
+    ) -> anyhow::Result<String> {
+        let mut value = self.expand.run_current(record, defs, context)?;
+
+        for filter in &self.filters {
+            value = filters.apply(filter, value)?;
+        }
+
+        Ok(value)
+    }
+}
+
+/// An [`Aggregate`] summarises the records of a table, optionally filtered by a where clause
+///
+/// ```bash
+/// {{count(country where team = "Allies")}}
+/// {{sum(country.code where team = "Allies") | thousands(",")}}
+/// ```
+///
+/// `value` is expanded relative to each record, e.g. `code` in `sum(country.code)`.
+/// Records with an empty value are skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    pub function: AggregateFn,
+    pub table_name: String,
+    pub value: Option<Expand>,
+    pub where_clause: Option<Condition>,
+    pub filters: Vec<Filter>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregateFn {
+    /// The number of records, or the number of records with a non-empty value
+    Count,
+    /// The total of the values, exact for integers
+    Sum,
+    /// The lowest value
+    Min,
+    /// The highest value
+    Max,
+    /// The mean of the values
+    Avg,
+}
+
+impl AggregateFn {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggregateFn::Count => "count",
+            AggregateFn::Sum => "sum",
+            AggregateFn::Min => "min",
+            AggregateFn::Max => "max",
+            AggregateFn::Avg => "avg",
+        }
+    }
+}
+
+impl Aggregate {
+    /// Every function other than `count` needs a value to aggregate
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.value.is_none() && self.function != AggregateFn::Count {
+            anyhow::bail!(
+                "`{name}` needs a field to aggregate, e.g. `{name}({table}.field)`",
+                name = self.function.name(),
+                table = self.table_name,
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn ctx_idx(&self) -> ContextIndex {
+        let table_name = self.table_name.clone();
+        match &self.where_clause {
+            None => ContextIndex::Table { table_name },
+            Some(where_clause) => ContextIndex::FilteredTableWhere {
+                table_name,
+                where_clause: where_clause.clone(),
+            },
+        }
+    }
+
+    /// Fails if the aggregate isn't [valid](Aggregate::validate), templates also check this when they are compiled
+    pub fn run(
+        &self,
+        record: &Record,
+        def: &Definition,
+        context: &InheritedContext,
+        filters: &Filters,
+    ) -> anyhow::Result<String> {
+        self.validate()?;
+
+        let ctx_idx = self.ctx_idx();
+        let records = def.index(&ctx_idx, record, context).with_context(|| {
+            format!(
+                "Failed to find records of `{}` to aggregate",
+                self.table_name
+            )
+        })?;
+
+        let mut count = 0;
+        let mut numbers = Vec::new();
+        for aggregated in records {
+            let aggregated = aggregated?;
+            let Some(expand) = &self.value else {
+                count += 1;
+                continue;
+            };
+
+            // the records are filtered, so the row is found in the table rather than counted
+            let name_record = || match aggregated.get("$id") {
+                Some(id) => format!("record `{id}` of `{}`", self.table_name),
+                None => {
+                    let row = def.get(&self.table_name).and_then(|table| {
+                        let n = table.iter().position(|r| std::ptr::eq(r, aggregated))?;
+                        Some(table.row(n))
+                    });
+                    match row {
+                        Some(row) => format!("row {row} of `{}`", self.table_name),
+                        None => format!("a record of `{}`", self.table_name),
+                    }
+                }
+            };
+
+            let value = expand
+                .run(aggregated, &def.defs, context)
+                .with_context(|| {
+                    format!(
+                        "Failed expansion during {} for {}",
+                        self.function.name(),
+                        name_record()
+                    )
+                })?;
+            if value.is_empty() {
+                continue;
+            }
+
+            count += 1;
+            if self.function != AggregateFn::Count {
+                match SortKey::new(&value) {
+                    SortKey::Text(_) => anyhow::bail!(
+                        "Expected `{value}` to be a number in {} for {}",
+                        name_record(),
+                        self.function.name(),
+                    ),
+                    number => numbers.push((number, value)),
+                }
+            }
+        }
+
+        let mut value = match self.function {
+            AggregateFn::Count => count.to_string(),
+            AggregateFn::Sum => sum(&numbers),
+            AggregateFn::Min => numbers
+                .iter()
+                .min_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default(),
+            AggregateFn::Max => numbers
+                .iter()
+                .max_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default(),
+            AggregateFn::Avg => match numbers.len() {
+                0 => String::new(),
+                len => {
+                    (numbers.iter().map(|(n, _)| n.as_f64()).sum::<f64>() / len as f64).to_string()
+                }
+            },
+        };
+
+        for filter in &self.filters {
+            value = filters.apply(filter, value)?;
+        }
+
+        Ok(value)
+    }
+}
+
+/// adds up integers exactly, falling back to floats if any number is a float or the total overflows
+fn sum(numbers: &[(SortKey, String)]) -> String {
+    let int_sum = numbers
+        .iter()
+        .try_fold(0_i64, |total, (number, _)| match number {
+            SortKey::Int(int) => total.checked_add(*int),
+            _ => None,
+        });
+
+    match int_sum {
+        Some(int_sum) => int_sum.to_string(),
+        None => numbers
+            .iter()
+            .map(|(number, _)| number.as_f64())
+            .sum::<f64>()
+            .to_string(),
+    }
 }
 
 /// A [`Block`] is a [`BlockExpr`] paired with some inner content made up of [`Node`]s
@@ -26,6 +290,21 @@ pub enum Expr {
 pub struct Block {
     pub expr: BlockExpr,
     pub nodes: Vec<Node>,
+
+    /// The opening tag of the block
+    pub span: Span,
+
+    /// The `{@ else if ___ @}` and `{@ else @}` branches that follow the inner content, in order.
+    ///
+    /// Only [`BlockExpr::If`] has else branches
+    pub else_branches: Vec<ElseBranch>,
+}
+
+/// An `{@ else if ___ @}` branch with a condition, or a final `{@ else @}` branch without one
+#[derive(Clone, PartialEq, Debug)]
+pub struct ElseBranch {
+    pub condition: Option<Condition>,
+    pub nodes: Vec<Node>,
 }
 
 /// Each [`Node`] is a plain [text](Node::Text) [`String`], an (inline) [`Expr`], or a [`Block`].
@@ -43,6 +322,29 @@ impl Node {
     }
 }
 
+/// The bytes of a template that something was parsed from, `start..end`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The line and column of the start of the span in `source`, both counting from 1
+    pub fn line_column(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        let column = before[line_start..].chars().count() + 1;
+
+        (line, column)
+    }
+}
+
 /// Context and Record are interchangable, they are both the exact same type.
 ///
 /// Context is used specifically in the context (no pun intended) of [lookup]s
@@ -226,6 +528,34 @@ impl Expand {
         }
     }
 
+    /// Expands against `current`, the record the template is being populated with, where a context that stands for a
+    /// value expands to its $id on its own, e.g. {{n}} inside {@ for n in [1, 2, 3] @}, see [`InheritedContext::value`].
+    /// The context of a loop over a table is a record, so {{country}} inside {@ for country in country @} is still the
+    /// `country` field of `current`.
+    ///
+    /// Records being filtered, sorted or grouped use [`Expand::run`] instead, so their fields are never mistaken for
+    /// a context with the same name.
+    pub fn run_current(
+        &self,
+        current: &Record,
+        defs: &HashMap<String, Table>,
+        context: &InheritedContext,
+    ) -> anyhow::Result<String> {
+        if self.path.is_empty() {
+            if let Some(block_ctx) = context.value(&self.field) {
+                let id = block_ctx.get("$id").ok_or_else(|| {
+                    anyhow!(
+                        "Failed expansion: context `{}` has no $id field",
+                        &self.field
+                    )
+                })?;
+                return Ok(id.clone());
+            }
+        }
+
+        self.run(current, defs, context)
+    }
+
     pub fn run(
         &self,
         record: &Record,
@@ -247,6 +577,60 @@ impl Expand {
 
         Ok(value.clone())
     }
+
+    /// The [`ColumnType`] declared for the field this expands to, if the table it comes from is known.
+    ///
+    /// `table` is the table of the record this is expanded from, which is only used without lookups. A field of a
+    /// block context, e.g. `c.code`, comes from an unknown table.
+    pub fn column_type(
+        &self,
+        table: Option<&Table>,
+        defs: &HashMap<String, Table>,
+        context: &InheritedContext,
+    ) -> Option<ColumnType> {
+        let table = match self.path.last() {
+            None => table?,
+            Some(lookup) => {
+                let index = lookup.index.as_ref().unwrap_or(&lookup.table_name);
+                if context.get(index).is_some() {
+                    return None;
+                }
+                defs.get(&lookup.table_name)?
+            }
+        };
+
+        table.column_type(&self.field)
+    }
+}
+
+/// Written back the way it is in a template, e.g. `country@Enemy.team.name`
+impl fmt::Display for Expand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for lookup in &self.path {
+            write_name(f, &lookup.table_name)?;
+            if let Some(index) = &lookup.index {
+                write!(f, "@")?;
+                write_name(f, index)?;
+            }
+            write!(f, ".")?;
+        }
+        write_name(f, &self.field)
+    }
+}
+
+// a field or table name, in backticks unless it is a plain word, e.g. `Enemy Country`, so that it parses back the same
+fn write_name(f: &mut fmt::Formatter<'_>, name: &str) -> fmt::Result {
+    let plain = name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && !["", "and", "or", "not"].contains(&name);
+
+    if plain {
+        write!(f, "{name}")
+    } else {
+        write!(f, "`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+    }
 }
 
 impl FromStr for Expr {
@@ -260,25 +644,123 @@ impl FromStr for Expr {
     }
 }
 
+/// A condition used by where clauses and if blocks, made up of [`Comparison`]s combined with
+/// `and`, `or`, `not` and grouped with parentheses.
+///
+/// `not` binds tightest, then `and`, then `or`.
+///
+/// # Examples
+///
+/// ```bash
+/// {@ for c in country where team = "Allies" and code > 50 @}
+///
+/// {@ if not (country.team = "Allies" or country.team = "Axis") @}
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Comparison(Comparison),
+    Not(Box<Condition>),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+}
+
+impl Condition {
+    /// Evaluates the condition, `and` and `or` stop evaluating as soon as the result is known
+    ///
+    /// See [`Comparison::matches`] for `record`, `table` and `current`
+    pub fn matches(
+        &self,
+        record: &Record,
+        table: Option<&Table>,
+        current: &Record,
+        def: &HashMap<String, Table>,
+        ctx: &InheritedContext,
+    ) -> anyhow::Result<bool> {
+        match self {
+            Condition::Comparison(comparison) => {
+                comparison.matches(record, table, current, def, ctx)
+            }
+            Condition::Not(condition) => Ok(!condition.matches(record, table, current, def, ctx)?),
+            Condition::And(conditions) => {
+                for condition in conditions {
+                    if !condition.matches(record, table, current, def, ctx)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Condition::Or(conditions) => {
+                for condition in conditions {
+                    if condition.matches(record, table, current, def, ctx)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl From<Comparison> for Condition {
+    fn from(comparison: Comparison) -> Self {
+        Condition::Comparison(comparison)
+    }
+}
+
+/// Written back the way it is in a template, with parentheses only where they are needed
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Comparison(comparison) => write!(f, "{comparison}"),
+            Condition::Not(condition) => match **condition {
+                Condition::Comparison(_) | Condition::Not(_) => write!(f, "not {condition}"),
+                Condition::And(_) | Condition::Or(_) => write!(f, "not ({condition})"),
+            },
+            Condition::And(conditions) => {
+                let conditions: Vec<String> = conditions
+                    .iter()
+                    .map(|condition| match condition {
+                        Condition::Or(_) => format!("({condition})"),
+                        _ => condition.to_string(),
+                    })
+                    .collect();
+                write!(f, "{}", conditions.join(" and "))
+            }
+            Condition::Or(conditions) => {
+                let conditions: Vec<String> = conditions.iter().map(ToString::to_string).collect();
+                write!(f, "{}", conditions.join(" or "))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Comparison {
     expand: Expand,
     comparator: Comparator,
-    value: Value,
+    value: Operand,
 }
 
 impl Comparison {
-    pub fn new(expand: Expand, comparator: Comparator, value: Value) -> Self {
+    pub fn new(expand: Expand, comparator: Comparator, value: impl Into<Operand>) -> Self {
         Comparison {
             expand,
             comparator,
-            value,
+            value: value.into(),
         }
     }
 
+    /// `record` is used to expand the left hand side of the comparison, in a where clause this is the
+    /// record being filtered. `table` is the table it is from, if known, so that a field with a declared
+    /// [`ColumnType`] is compared as that type, see [`Comparator::compare_typed`].
+    ///
+    /// `current` is used to expand an [`Operand::Expand`] on the right hand side, this is the record
+    /// the template is currently being populated with, so a block context on its own is its $id there.
     pub fn matches(
         &self,
         record: &Record,
+        table: Option<&Table>,
+        current: &Record,
         def: &HashMap<String, Table>,
         ctx: &InheritedContext,
     ) -> anyhow::Result<bool> {
@@ -286,8 +768,31 @@ impl Comparison {
             .expand
             .run(record, def, ctx)
             .with_context(|| format!("Failed expansion during comparison: `{:?}`", &self.expand))?;
+        let column_type = self.expand.column_type(table, def, ctx);
+
+        let where_value = match &self.value {
+            Operand::Value(where_value) => where_value,
+            Operand::Expand(expand) => {
+                let other_value = expand
+                    .run_current(current, def, ctx)
+                    .with_context(|| format!("Failed expansion during comparison: `{expand:?}`"))?;
+                return match column_type.or_else(|| expand.column_type(None, def, ctx)) {
+                    Some(column_type) => {
+                        self.comparator
+                            .compare_typed(column_type, &value, &other_value)
+                    }
+                    None => Ok(self.comparator.compare_text_or_number(&value, &other_value)),
+                };
+            }
+        };
+
+        if let Some(column_type) = column_type {
+            return self
+                .comparator
+                .compare_typed(column_type, &value, &where_value.to_string());
+        }
 
-        let matches = match &self.value {
+        let matches = match where_value {
             Value::Int(where_value) => self.comparator.compare(
                 &value
                     .parse::<i64>()
@@ -313,6 +818,50 @@ impl Comparison {
     }
 }
 
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.expand, self.comparator, self.value)
+    }
+}
+
+/// The right hand side of a [`Comparison`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// A literal value, e.g. `team = "Allies"`
+    Value(Value),
+
+    /// Another field, e.g. `team != enemy.team`
+    Expand(Expand),
+}
+
+impl From<Value> for Operand {
+    fn from(value: Value) -> Self {
+        Operand::Value(value)
+    }
+}
+
+impl From<Expand> for Operand {
+    fn from(expand: Expand) -> Self {
+        Operand::Expand(expand)
+    }
+}
+
+/// Text values are written in quotes, with their quotes and backslashes escaped, like they are in a template
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Value(Value::Text(text)) => {
+                write!(f, "\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Operand::Value(value) => write!(f, "{value}"),
+            Operand::Expand(expand) => write!(f, "{expand}"),
+        }
+    }
+}
+
+/// A literal value in a template, e.g. `"Allies"` in `team = "Allies"` or `4` in `pad(4, "0")`
+///
+/// The arguments of a custom [`FilterFn`] are these values, it is exported as `FilterArg`
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Int(i64),
@@ -321,6 +870,24 @@ pub enum Value {
     Text(String),
 }
 
+impl Value {
+    /// A [`Record`] with this value as its `$id`, so that the value can be used as a [`Context`]
+    pub fn to_record(&self) -> Record {
+        Record::from([("$id".to_string(), self.to_string())])
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(int) => write!(f, "{int}"),
+            Value::Uint(uint) => write!(f, "{uint}"),
+            Value::Float(float) => write!(f, "{float}"),
+            Value::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Comparator {
     Equal,
@@ -331,6 +898,20 @@ pub enum Comparator {
     LessThanOrEqual,
 }
 
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Comparator::Equal => "=",
+            Comparator::NotEqual => "!=",
+            Comparator::GreaterThan => ">",
+            Comparator::LessThan => "<",
+            Comparator::GreaterThanOrEqual => ">=",
+            Comparator::LessThanOrEqual => "<=",
+        };
+        write!(f, "{symbol}")
+    }
+}
+
 impl Comparator {
     pub fn compare<T: PartialEq + PartialOrd>(&self, a: T, b: T) -> bool {
         match self {
@@ -342,30 +923,388 @@ impl Comparator {
             Comparator::LessThanOrEqual => a <= b,
         }
     }
+
+    /// Compares `value`, from a field declared as `column_type`, with `other`, which is checked and written the same
+    /// way as the values of that field first. Empty values are compared as text
+    pub fn compare_typed(
+        &self,
+        column_type: ColumnType,
+        value: &str,
+        other: &str,
+    ) -> anyhow::Result<bool> {
+        let other = column_type
+            .normalize(other)
+            .with_context(|| format!("Failed to compare with a `{}` field", column_type.name()))?;
+        if value.is_empty() || other.is_empty() {
+            return Ok(self.compare(value, other.as_str()));
+        }
+
+        let compared = match column_type {
+            ColumnType::Int | ColumnType::Float => self.compare_text_or_number(value, &other),
+            ColumnType::Text | ColumnType::Bool | ColumnType::Date => {
+                self.compare(value, other.as_str())
+            }
+        };
+        Ok(compared)
+    }
+
+    /// Compares two populated values as numbers if they both are numbers, or as text otherwise
+    pub fn compare_text_or_number(&self, a: &str, b: &str) -> bool {
+        if let (Ok(a), Ok(b)) = (a.parse::<i64>(), b.parse::<i64>()) {
+            return self.compare(a, b);
+        }
+
+        match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) if a.is_finite() && b.is_finite() => self.compare(a, b),
+            _ => self.compare(a, b),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum BlockExpr {
     ForTag(ForTag),
-    If(Comparison),
+    If(Condition),
+    Pop(PopTag),
+    Set(Assignment),
+    With(Assignment),
+    Macro(MacroTag),
+    Call(CallTag),
+
+    /// Imports the macros defined in another template file, see [`MacroTag`]
+    Import(String),
 }
 
 impl BlockExpr {
     /// return the name used to close this [`BlockExpr`]
-    fn close(&self) -> &'static str {
+    ///
+    /// [`BlockExpr::Pop`], [`BlockExpr::Set`], [`BlockExpr::Call`] and [`BlockExpr::Import`]
+    /// have no inner content and so are never closed
+    fn close(&self) -> Option<&'static str> {
         match self {
-            BlockExpr::ForTag(_) => "for",
-            BlockExpr::If(_) => "if",
+            BlockExpr::ForTag(_) => Some("for"),
+            BlockExpr::If(_) => Some("if"),
+            BlockExpr::Pop(_) => None,
+            BlockExpr::Set(_) => None,
+            BlockExpr::With(_) => Some("with"),
+            BlockExpr::Macro(_) => Some("macro"),
+            BlockExpr::Call(_) => None,
+            BlockExpr::Import(_) => None,
         }
     }
 }
 
+/// Binds a value to a name in the [`InheritedContext`]
+///
+/// ```bash
+/// {@ set enemy = country.team@Enemy.code @}
+/// # for the rest of the enclosing block
+///
+/// {@ with side = "Allies" @}...{@ end with @}
+/// # only inside the with block
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub value: AssignedValue,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssignedValue {
+    /// A literal value, e.g. `"Allies"` or `10`
+    Value(Value),
+
+    /// The record found by following lookups that end with an explicit index, e.g. `country@Enemy`
+    Record(Vec<Lookup>),
+
+    /// An expression, just like the inside of `{{ }}`
+    Expr(ExprKind),
+}
+
+impl Assignment {
+    /// Evaluates the value and binds it to the name in `ctx`, see [`AssignedValue::evaluate`]
+    pub fn bind(
+        &self,
+        ctx: &mut InheritedContext,
+        record: &Record,
+        def: &Definition,
+        filters: &Filters,
+    ) -> anyhow::Result<()> {
+        let (context, records) = self
+            .value
+            .evaluate(record, def, ctx, filters)
+            .with_context(|| format!("Failed to set `{}`", self.name))?;
+        ctx.bind(self.name.clone(), context, records);
+
+        Ok(())
+    }
+}
+
+impl AssignedValue {
+    /// Evaluates this value into a context to bind to a name, along with its records if it is a group
+    ///
+    /// * [`AssignedValue::Record`] is the record it finds
+    /// * An [`Expand`] naming a block context is that whole context, like renaming it
+    /// * An [`Expand`] whose value is the `$id` of a record in the table named after its field is that record,
+    ///   so `{@ set t = country.team @}` makes `{{t.name}}` available
+    /// * Anything else is a record with the value as its `$id`, so `{{name}}` populates the value
+    pub fn evaluate(
+        &self,
+        record: &Record,
+        def: &Definition,
+        ctx: &InheritedContext,
+        filters: &Filters,
+    ) -> anyhow::Result<(Record, Option<Vec<Record>>)> {
+        let value = match self {
+            AssignedValue::Value(value) => value.to_string(),
+            AssignedValue::Record(path) => {
+                let mut found = record;
+                for lookup in path {
+                    found = lookup.run(found, &def.defs, ctx)?;
+                }
+
+                return Ok((found.clone(), None));
+            }
+            AssignedValue::Expr(ExprKind::Expand(expand))
+                if expand.path.is_empty() && ctx.contains_key(&expand.field) =>
+            {
+                let context = ctx.get(&expand.field).cloned().unwrap_or_default();
+                let records = ctx.records(&expand.field).map(<[Record]>::to_vec);
+                return Ok((context, records));
+            }
+            AssignedValue::Expr(expr) => expr.run(record, def, ctx, filters)?,
+        };
+
+        let looked_up = match self {
+            AssignedValue::Expr(ExprKind::Expand(expand)) => def
+                .defs
+                .get(&expand.field)
+                .and_then(|table| table.index(&value).ok().flatten()),
+            _ => None,
+        };
+
+        let context = match looked_up {
+            Some(looked_up) => looked_up.clone(),
+            None => Record::from([("$id".to_string(), value)]),
+        };
+
+        Ok((context, None))
+    }
+}
+
+/// Defines a macro, a template that can be populated with arguments wherever it is called
+///
+/// ```bash
+/// {@ macro morale_event(country, amount) @}{{country}} gains {{amount}} morale{@ end macro @}
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroTag {
+    pub name: String,
+    pub params: Vec<String>,
+}
+
+/// Calls a macro, binding each argument to the macro's parameter in the same position
+///
+/// ```bash
+/// {@ call morale_event(country, "10") @}
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallTag {
+    pub name: String,
+    pub args: Vec<AssignedValue>,
+}
+
+/// Includes another template file, which is populated in place of the tag
+///
+/// # Examples
+///
+/// ```bash
+/// {@ pop macros/header.txt @}
+///
+/// # the included template can use {{team}} and {{side}}
+/// {@ pop macros/header.txt with country.team as team, "Allies" as side @}
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PopTag {
+    /// The path to the included template, relative to the directory of the including template, or to where popvars is
+    /// run for the top level template
+    pub path: String,
+    pub with_clause: Vec<PopBinding>,
+}
+
+/// `field as new_field` or `"value" as new_field` in the with clause of a [`PopTag`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PopBinding {
+    pub value: PopValue,
+    pub new_field: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PopValue {
+    /// The value of a field in the current context.
+    ///
+    /// If this names a block context, such as the context of a for loop, the whole context is renamed instead
+    Expand(Expand),
+
+    /// A literal value written in double quotes, provided as a context with the value as its `$id`
+    Text(String),
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct ForTag {
     pub new_context_name: String,
-    lookup: Lookup,
-    where_clause: Option<Comparison>,
+    source: ForSource,
+    where_clause: Option<Condition>,
     pub other_clause: bool,
+    group_by: Option<Expand>,
+    order: LoopOrder,
+}
+
+/// What a [`ForTag`] loops over
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForSource {
+    /// Each record in a table, e.g. `{@ for c in country @}`
+    Table(Lookup),
+
+    /// Each value in an inline list, e.g. `{@ for n in [1, 2, 3] @}`
+    Values(Vec<Value>),
+
+    /// Each record in a group from a surrounding group by loop, e.g. `{@ for c in team.records @}`
+    GroupRecords(String),
+}
+
+/// Sorts and limits the records a [`ForTag`] loops over, e.g. `order by code desc reverse offset 2 limit 5`
+///
+/// The records are sorted first, then reversed, then offset and finally limited,
+/// whichever order the clauses are written in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoopOrder {
+    pub order_by: Option<OrderBy>,
+    pub reverse: bool,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// `order by code desc`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBy {
+    pub expand: Expand,
+    pub descending: bool,
+}
+
+impl LoopOrder {
+    pub fn is_unordered(&self) -> bool {
+        self == &LoopOrder::default()
+    }
+
+    /// Sorts and limits `records`, the [`OrderBy`] field is expanded relative to each record.
+    ///
+    /// `table` is the table the records are from, if known, so that a field with a declared [`ColumnType`] is
+    /// sorted as that type, see [`SortKey::typed`]
+    pub fn apply<'a>(
+        &self,
+        records: impl Iterator<Item = &'a Record>,
+        table: Option<&Table>,
+        defs: &HashMap<String, Table>,
+        ctx: &InheritedContext,
+    ) -> anyhow::Result<Vec<&'a Record>> {
+        let mut records: Vec<&'a Record> = records.collect();
+
+        if let Some(OrderBy { expand, descending }) = &self.order_by {
+            let column_type = expand.column_type(table, defs, ctx);
+            let mut keyed = records
+                .into_iter()
+                .map(|record| {
+                    let value = expand.run(record, defs, ctx).with_context(|| {
+                        format!("Failed expansion during order by: `{expand:?}`")
+                    })?;
+                    Ok((SortKey::typed(&value, column_type), record))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            // a stable sort, so records with equal keys stay in table order
+            keyed.sort_by(|(a, _), (b, _)| match descending {
+                true => b.cmp(a),
+                false => a.cmp(b),
+            });
+
+            records = keyed.into_iter().map(|(_, record)| record).collect();
+        }
+
+        if self.reverse {
+            records.reverse();
+        }
+
+        Ok(records
+            .into_iter()
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+}
+
+/// A populated value interpreted the same way as [`Comparator::compare_text_or_number`]
+///
+/// Numbers are ordered before text so that sorting a mix of both is consistent
+#[derive(Debug, PartialEq)]
+enum SortKey {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl SortKey {
+    fn as_f64(&self) -> f64 {
+        match self {
+            SortKey::Int(int) => *int as f64,
+            SortKey::Float(float) => *float,
+            SortKey::Text(_) => f64::NAN,
+        }
+    }
+
+    /// Text, bool and date fields are sorted as text, ISO dates sort in order that way. Numbers are recognised
+    /// in int and float fields as well as in fields without a declared type
+    fn typed(value: &str, column_type: Option<ColumnType>) -> Self {
+        match column_type {
+            Some(ColumnType::Text | ColumnType::Bool | ColumnType::Date) => {
+                SortKey::Text(value.to_string())
+            }
+            Some(ColumnType::Int | ColumnType::Float) | None => SortKey::new(value),
+        }
+    }
+
+    fn new(value: &str) -> Self {
+        if let Ok(int) = value.parse::<i64>() {
+            return SortKey::Int(int);
+        }
+
+        match value.parse::<f64>() {
+            Ok(float) if float.is_finite() => SortKey::Float(float),
+            _ => SortKey::Text(value.to_string()),
+        }
+    }
+}
+
+impl Eq for SortKey {}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (SortKey::Int(a), SortKey::Int(b)) => a.cmp(b),
+            (SortKey::Int(a), SortKey::Float(b)) => (*a as f64).total_cmp(b),
+            (SortKey::Float(a), SortKey::Int(b)) => a.total_cmp(&(*b as f64)),
+            (SortKey::Float(a), SortKey::Float(b)) => a.total_cmp(b),
+            (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+            (SortKey::Text(_), _) => Ordering::Greater,
+            (_, SortKey::Text(_)) => Ordering::Less,
+        }
+    }
 }
 
 impl FromStr for ForTag {
@@ -378,11 +1317,27 @@ impl FromStr for ForTag {
 }
 
 impl ForTag {
-    pub fn ctx_idx(&self) -> ContextIndex {
-        let table_name = self.lookup.table_name.clone();
+    pub fn ctx_idx(&self) -> anyhow::Result<ContextIndex> {
+        let lookup = match &self.source {
+            ForSource::Table(lookup) => lookup,
+            ForSource::Values(values) => {
+                self.only_ordered("a list of values")?;
+                return Ok(self.ordered(ContextIndex::ValueList(
+                    values.iter().map(Value::to_record).collect(),
+                )));
+            }
+            ForSource::GroupRecords(context_name) => {
+                self.only_ordered("the records of a group")?;
+                return Ok(self.ordered(ContextIndex::GroupRecords {
+                    context_name: context_name.clone(),
+                }));
+            }
+        };
+
+        let table_name = lookup.table_name.clone();
         let where_clause = self.where_clause.clone();
         let other_clause = self.other_clause;
-        match (where_clause, other_clause) {
+        let ctx_idx = match (where_clause, other_clause) {
             (None, false) => ContextIndex::Table { table_name },
             (Some(where_clause), false) => ContextIndex::FilteredTableWhere {
                 table_name,
@@ -390,13 +1345,47 @@ impl ForTag {
             },
             (None, true) => ContextIndex::FilteredTableOther {
                 table_name,
-                index: self.lookup.index.clone(),
+                index: lookup.index.clone(),
             },
             (Some(where_clause), true) => ContextIndex::FilteredTableOtherWhere {
                 table_name,
                 where_clause,
-                index: self.lookup.index.clone(),
+                index: lookup.index.clone(),
             },
+        };
+
+        let ctx_idx = self.ordered(ctx_idx);
+
+        match &self.group_by {
+            Some(key) => Ok(ContextIndex::GroupBy {
+                index: Box::new(ctx_idx),
+                key: key.clone(),
+            }),
+            None => Ok(ctx_idx),
+        }
+    }
+
+    /// loops over anything other than a table can only be sorted and limited
+    fn only_ordered(&self, source: &str) -> anyhow::Result<()> {
+        if self.where_clause.is_some() || self.other_clause || self.group_by.is_some() {
+            anyhow::bail!(
+                "Loop `{}` over {source} can't have a where clause, a group by or be an other loop",
+                self.new_context_name
+            );
+        }
+
+        Ok(())
+    }
+
+    /// wraps `ctx_idx` to sort and limit its records, if this loop has any [`LoopOrder`] clauses
+    fn ordered(&self, ctx_idx: ContextIndex) -> ContextIndex {
+        if self.order.is_unordered() {
+            return ctx_idx;
+        }
+
+        ContextIndex::Ordered {
+            index: Box::new(ctx_idx),
+            order: self.order.clone(),
         }
     }
 }
@@ -0,0 +1,542 @@
+//! Checks a compiled template against a [`Definition`] without populating it, see [`Template::check`]
+//!
+//! [`Template::check`]: crate::Template::check
+
+use std::collections::{HashMap, HashSet};
+
+use super::*;
+use crate::template::{CompiledBlock, CompiledCall, CompiledNode, Location, LOOP_CONTEXT};
+
+/// The fields of the `loop` context, see [`LOOP_CONTEXT`]
+const LOOP_FIELDS: &[&str] = &["index", "index0", "first", "last", "length"];
+
+/// The only field of a context made from a value, see [`Value::to_record`]
+const ID_FIELDS: &[&str] = &["$id"];
+
+/// Checks `nodes` against `def`, returning every problem found along with where it was found
+pub(crate) fn check(nodes: &[CompiledNode], def: &Definition) -> Vec<(Location, String)> {
+    let mut checker = Checker {
+        def,
+        loops: HashSet::new(),
+        location: Location::default(),
+        problems: Vec::new(),
+    };
+    checker.collect_loops(nodes);
+
+    let scope = Scope {
+        record: Kind::Table("vars".into()),
+        contexts: HashMap::new(),
+        values: HashSet::new(),
+    };
+    checker.check_nodes(nodes, &scope);
+
+    checker.problems
+}
+
+/// What is known about the fields of a record or context, without knowing which record it is
+#[derive(Debug, Clone)]
+enum Kind {
+    /// A record of the named table, `vars` or a def
+    Table(String),
+
+    /// A context with only these fields, e.g. `loop`
+    Fields(&'static [&'static str]),
+
+    /// A group of a group by loop over the named table, see [`ContextIndex::GroupBy`]
+    Group(String),
+
+    /// The fields of another kind along with these, e.g. the record of an included template with a with clause
+    Extended(Box<Kind>, Vec<String>),
+
+    /// Any field may exist, e.g. in the parameters of a macro
+    Unknown,
+}
+
+impl Kind {
+    fn table(&self) -> Option<&str> {
+        match self {
+            Kind::Table(table) => Some(table),
+            Kind::Extended(kind, _) => kind.table(),
+            _ => None,
+        }
+    }
+}
+
+/// The static equivalent of the record and [`InheritedContext`] a node is populated with
+#[derive(Debug, Clone)]
+struct Scope {
+    record: Kind,
+    contexts: HashMap<String, Kind>,
+
+    /// the contexts that stand for a single value, see [`InheritedContext::value`]
+    values: HashSet<String>,
+}
+
+impl Scope {
+    /// See [`InheritedContext::insert`]
+    fn insert(&mut self, name: String, kind: Kind) {
+        self.values.remove(&name);
+        self.contexts.insert(name, kind);
+    }
+
+    /// See [`InheritedContext::insert_value`]
+    fn insert_value(&mut self, name: String, kind: Kind) {
+        self.values.insert(name.clone());
+        self.contexts.insert(name, kind);
+    }
+}
+
+struct Checker<'d> {
+    def: &'d Definition,
+
+    /// the names of the contexts of every loop in the template
+    loops: HashSet<String>,
+
+    /// the expression or block tag being checked
+    location: Location,
+
+    problems: Vec<(Location, String)>,
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, problem: String) {
+        let Location { source, span } = self.location;
+        let duplicate = self.problems.iter().any(|(location, existing)| {
+            location.source == source && location.span.start == span.start && existing == &problem
+        });
+
+        if !duplicate {
+            self.problems.push((self.location, problem));
+        }
+    }
+
+    fn collect_loops(&mut self, nodes: &[CompiledNode]) {
+        for node in nodes {
+            let block = match node {
+                CompiledNode::Block(block) => block,
+                CompiledNode::Call(call) => {
+                    self.collect_loops(&call.nodes);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if let BlockExpr::ForTag(for_tag) = &block.expr {
+                self.loops.insert(for_tag.new_context_name.clone());
+            }
+            self.collect_loops(&block.nodes);
+            for branch in &block.else_branches {
+                self.collect_loops(&branch.nodes);
+            }
+        }
+    }
+
+    /// Each [`BlockExpr::Set`] applies to the rest of `nodes`, just like when they are populated
+    fn check_nodes(&mut self, nodes: &[CompiledNode], scope: &Scope) {
+        let mut scope = scope.clone();
+
+        for node in nodes {
+            match node {
+                CompiledNode::Text(_) => {}
+                CompiledNode::Expr(expr, location) => {
+                    self.location = *location;
+                    self.check_expr(&expr.kind, &scope);
+                }
+                CompiledNode::Block(block) => {
+                    self.location = block.location;
+                    match &block.expr {
+                        BlockExpr::Set(assignment) => {
+                            self.check_name(&assignment.name);
+                            let kind = self.assigned_kind(&assignment.value, &scope);
+                            scope.insert_value(assignment.name.clone(), kind);
+                        }
+                        _ => self.check_block(block, &scope),
+                    }
+                }
+                CompiledNode::Call(call) => {
+                    self.location = call.location;
+                    self.check_call(call, &scope);
+                }
+            }
+        }
+    }
+
+    fn check_block(&mut self, block: &CompiledBlock, scope: &Scope) {
+        match &block.expr {
+            BlockExpr::ForTag(for_tag) => {
+                self.check_name(&for_tag.new_context_name);
+                let kind = self.check_for_tag(for_tag, scope);
+                let is_value = matches!(kind, Kind::Group(_))
+                    || matches!(for_tag.source, ForSource::Values(_));
+
+                let mut inner = scope.clone();
+                inner.insert(LOOP_CONTEXT.into(), Kind::Fields(LOOP_FIELDS));
+                if is_value {
+                    inner.insert_value(for_tag.new_context_name.clone(), kind);
+                } else {
+                    inner.insert(for_tag.new_context_name.clone(), kind);
+                }
+                self.check_nodes(&block.nodes, &inner);
+            }
+            BlockExpr::If(condition) => {
+                self.check_condition(condition, &scope.record, scope);
+                self.check_nodes(&block.nodes, scope);
+
+                for branch in &block.else_branches {
+                    if let Some(condition) = &branch.condition {
+                        self.location = block.location;
+                        self.check_condition(condition, &scope.record, scope);
+                    }
+                    self.check_nodes(&branch.nodes, scope);
+                }
+            }
+            BlockExpr::Pop(pop_tag) => {
+                let mut included = scope.clone();
+                let mut fields = Vec::new();
+
+                for binding in &pop_tag.with_clause {
+                    self.check_name(&binding.new_field);
+                    match &binding.value {
+                        PopValue::Text(_) => {
+                            included
+                                .insert_value(binding.new_field.clone(), Kind::Fields(ID_FIELDS));
+                        }
+                        PopValue::Expand(expand) if expand.path.is_empty() => {
+                            match scope.contexts.get(&expand.field) {
+                                Some(kind) if scope.values.contains(&expand.field) => {
+                                    included.insert_value(binding.new_field.clone(), kind.clone());
+                                }
+                                Some(kind) => {
+                                    included.insert(binding.new_field.clone(), kind.clone());
+                                }
+                                None => {
+                                    self.check_expand(expand, &scope.record, scope);
+                                    fields.push(binding.new_field.clone());
+                                }
+                            }
+                        }
+                        PopValue::Expand(expand) => {
+                            self.check_expand(expand, &scope.record, scope);
+                            fields.push(binding.new_field.clone());
+                        }
+                    }
+                }
+
+                if !fields.is_empty() {
+                    included.record = Kind::Extended(Box::new(scope.record.clone()), fields);
+                }
+                self.check_nodes(&block.nodes, &included);
+            }
+            BlockExpr::With(assignment) => {
+                self.check_name(&assignment.name);
+                let kind = self.assigned_kind(&assignment.value, scope);
+
+                let mut with = scope.clone();
+                with.insert_value(assignment.name.clone(), kind);
+                self.check_nodes(&block.nodes, &with);
+            }
+            // set is checked by check_nodes, macros where they are called, and calls are CompiledNode::Call
+            BlockExpr::Set(_) | BlockExpr::Macro(_) | BlockExpr::Import(_) | BlockExpr::Call(_) => {
+            }
+        }
+    }
+
+    fn check_call(&mut self, call: &CompiledCall, scope: &Scope) {
+        let mut inner = scope.clone();
+        for (param, arg) in call.params.iter().zip(&call.call_tag.args) {
+            self.check_name(param);
+            let kind = self.assigned_kind(arg, scope);
+            inner.insert_value(param.clone(), kind);
+        }
+
+        self.check_nodes(&call.nodes, &inner);
+    }
+
+    /// Checks the tag of a for loop, returning the kind of its context
+    fn check_for_tag(&mut self, for_tag: &ForTag, scope: &Scope) -> Kind {
+        let lookup = match &for_tag.source {
+            ForSource::Values(_) => {
+                let kind = Kind::Fields(ID_FIELDS);
+                self.check_order(&for_tag.order, &kind, scope);
+                return kind;
+            }
+            ForSource::GroupRecords(context_name) => {
+                let kind = match scope.contexts.get(context_name) {
+                    Some(Kind::Group(table)) => Kind::Table(table.clone()),
+                    Some(_) => {
+                        self.problem(format!(
+                            "`{context_name}` has no records, only the contexts of group by loops do"
+                        ));
+                        Kind::Unknown
+                    }
+                    None => {
+                        self.problem(format!(
+                            "`{context_name}` isn't the context of a surrounding group by loop"
+                        ));
+                        Kind::Unknown
+                    }
+                };
+                self.check_order(&for_tag.order, &kind, scope);
+                return kind;
+            }
+            ForSource::Table(lookup) => lookup,
+        };
+
+        if !self.check_table(&lookup.table_name) {
+            return Kind::Unknown;
+        }
+        let kind = Kind::Table(lookup.table_name.clone());
+
+        if for_tag.other_clause {
+            let index = lookup.index.as_ref().unwrap_or(&lookup.table_name);
+            if !self.has_field(&scope.record, index) {
+                self.problem(format!(
+                    "{} has no field `{index}` to find the other records of `{}` with",
+                    name(&scope.record, "the record"),
+                    lookup.table_name
+                ));
+            }
+        }
+
+        if let Some(where_clause) = &for_tag.where_clause {
+            self.check_condition(where_clause, &kind, scope);
+        }
+        self.check_order(&for_tag.order, &kind, scope);
+
+        match &for_tag.group_by {
+            Some(key) => {
+                self.check_expand(key, &kind, scope);
+                Kind::Group(lookup.table_name.clone())
+            }
+            None => kind,
+        }
+    }
+
+    fn check_order(&mut self, order: &LoopOrder, kind: &Kind, scope: &Scope) {
+        if let Some(order_by) = &order.order_by {
+            self.check_expand(&order_by.expand, kind, scope);
+        }
+    }
+
+    fn check_expr(&mut self, expr: &ExprKind, scope: &Scope) {
+        match expr {
+            ExprKind::Expand(expand) => self.check_current(expand, scope),
+            ExprKind::Filtered(filtered) => self.check_current(&filtered.expand, scope),
+            ExprKind::Aggregate(aggregate) => {
+                if !self.check_table(&aggregate.table_name) {
+                    return;
+                }
+                let kind = Kind::Table(aggregate.table_name.clone());
+
+                if let Some(value) = &aggregate.value {
+                    self.check_expand(value, &kind, scope);
+                }
+                if let Some(where_clause) = &aggregate.where_clause {
+                    self.check_condition(where_clause, &kind, scope);
+                }
+            }
+        }
+    }
+
+    /// The left hand side of each comparison is expanded from `filtered`, see [`Comparison::matches`]
+    fn check_condition(&mut self, condition: &Condition, filtered: &Kind, scope: &Scope) {
+        match condition {
+            Condition::Comparison(comparison) => {
+                self.check_expand(&comparison.expand, filtered, scope);
+                if let Operand::Expand(expand) = &comparison.value {
+                    self.check_current(expand, scope);
+                }
+            }
+            Condition::Not(condition) => self.check_condition(condition, filtered, scope),
+            Condition::And(conditions) | Condition::Or(conditions) => {
+                for condition in conditions {
+                    self.check_condition(condition, filtered, scope);
+                }
+            }
+        }
+    }
+
+    /// Checks an assigned value, returning the kind of the context it is bound as, see [`AssignedValue::evaluate`]
+    fn assigned_kind(&mut self, value: &AssignedValue, scope: &Scope) -> Kind {
+        match value {
+            AssignedValue::Value(_) => Kind::Fields(ID_FIELDS),
+            AssignedValue::Record(path) => self
+                .check_path(path, &scope.record, scope)
+                .map_or(Kind::Unknown, |(kind, _)| kind),
+            AssignedValue::Expr(ExprKind::Expand(expand))
+                if expand.path.is_empty() && scope.contexts.contains_key(&expand.field) =>
+            {
+                scope.contexts[&expand.field].clone()
+            }
+            AssignedValue::Expr(expr) => {
+                self.check_expr(expr, scope);
+                match expr {
+                    ExprKind::Expand(expand) if self.def.defs.contains_key(&expand.field) => {
+                        Kind::Table(expand.field.clone())
+                    }
+                    _ => Kind::Fields(ID_FIELDS),
+                }
+            }
+        }
+    }
+
+    /// Checks an expand of the record being populated, where a context that stands for a value is its `$id` on its
+    /// own, see [`Expand::run_current`]
+    fn check_current(&mut self, expand: &Expand, scope: &Scope) {
+        if expand.path.is_empty() && scope.values.contains(&expand.field) {
+            return;
+        }
+        if let Some(kind) = scope
+            .contexts
+            .get(&expand.field)
+            .filter(|_| expand.path.is_empty())
+        {
+            if !self.has_field(&scope.record, &expand.field) {
+                // the context of a loop over a table that doesn't exist has already been reported
+                if !matches!(kind, Kind::Unknown) {
+                    self.problem(format!(
+                        "`{0}` is a record, so on its own it is a field of {1}, use `{0}.$id` for its `$id`",
+                        expand.field,
+                        name(&scope.record, "the record")
+                    ));
+                }
+                return;
+            }
+        }
+
+        self.check_expand(expand, &scope.record, scope);
+    }
+
+    /// `record` is the kind of the record `expand` is expanded from, see [`Expand::run`]
+    fn check_expand(&mut self, expand: &Expand, record: &Kind, scope: &Scope) {
+        let Some((kind, kind_name)) = self.check_path(&expand.path, record, scope) else {
+            return;
+        };
+
+        if self.has_field(&kind, &expand.field) {
+            return;
+        }
+        if expand.path.is_empty() && self.loops.contains(&expand.field) {
+            self.outside_loop(&expand.field);
+        } else {
+            self.problem(format!("{kind_name} has no field `{}`", expand.field));
+        }
+    }
+
+    /// Follows the lookups of `path` from `record`, see [`Lookup::run`]
+    ///
+    /// Returns the kind of the context found and a name for it, or `None` if a lookup has a problem
+    fn check_path(
+        &mut self,
+        path: &[Lookup],
+        record: &Kind,
+        scope: &Scope,
+    ) -> Option<(Kind, String)> {
+        let mut kind = record.clone();
+        let mut kind_name = name(record, "the record");
+
+        for lookup in path {
+            let index = lookup.index.as_ref().unwrap_or(&lookup.table_name);
+
+            if let Some(context) = scope.contexts.get(index) {
+                if index == LOOP_CONTEXT {
+                    self.check_hidden_by_loop(lookup, &kind);
+                }
+                kind = context.clone();
+                kind_name = name(context, &format!("`{index}`"));
+                continue;
+            }
+
+            if !self.has_field(&kind, index) && self.loops.contains(index) {
+                self.outside_loop(index);
+                return None;
+            }
+            if !self.check_table(&lookup.table_name) {
+                return None;
+            }
+            if !self.has_field(&kind, index) {
+                self.problem(format!(
+                    "{kind_name} has no field `{index}` to look up `{}` with",
+                    lookup.table_name
+                ));
+                return None;
+            }
+
+            kind = Kind::Table(lookup.table_name.clone());
+            kind_name = format!("`{}`", lookup.table_name);
+        }
+
+        Some((kind, kind_name))
+    }
+
+    /// Fails if there is no table named `table_name`
+    fn check_table(&mut self, table_name: &str) -> bool {
+        if self.def.get(table_name).is_some() {
+            return true;
+        }
+
+        self.problem(format!("There is no table named `{table_name}`"));
+        false
+    }
+
+    fn has_field(&self, kind: &Kind, field: &str) -> bool {
+        match kind {
+            Kind::Table(table) => self
+                .def
+                .get(table)
+                .map_or(true, |table| table.has_field(field)),
+            Kind::Fields(fields) => fields.iter().any(|f| *f == field),
+            Kind::Group(_) => ID_FIELDS.iter().any(|f| *f == field),
+            Kind::Extended(kind, fields) => {
+                fields.iter().any(|f| f == field) || self.has_field(kind, field)
+            }
+            Kind::Unknown => true,
+        }
+    }
+
+    /// Fails if `name` is `loop`, which would hide the metadata of for loops, see [`LOOP_CONTEXT`]
+    fn check_name(&mut self, name: &str) {
+        if name == LOOP_CONTEXT {
+            self.problem(format!(
+                "`{LOOP_CONTEXT}` is reserved for the metadata of for loops, use another name"
+            ));
+        }
+    }
+
+    /// Fails if a lookup with `loop` inside a for loop would have used a field of `record` or a table named `loop`,
+    /// since it finds the metadata of the loop instead
+    fn check_hidden_by_loop(&mut self, lookup: &Lookup, record: &Kind) {
+        let has_field = record
+            .table()
+            .and_then(|table| self.def.get(table))
+            .map_or(false, |table| table.has_field(LOOP_CONTEXT));
+
+        let hidden = if has_field {
+            format!(
+                "the field `{LOOP_CONTEXT}` of {}",
+                name(record, "the record")
+            )
+        } else if lookup.index.is_none() && self.def.get(LOOP_CONTEXT).is_some() {
+            format!("the table `{LOOP_CONTEXT}`")
+        } else {
+            return;
+        };
+        self.problem(format!(
+            "Inside a for loop `{LOOP_CONTEXT}` is the metadata of the loop, which hides {hidden}"
+        ));
+    }
+
+    fn outside_loop(&mut self, context_name: &str) {
+        self.problem(format!(
+            "`{context_name}` is the context of a loop and can only be used inside that loop"
+        ));
+    }
+}
+
+/// e.g. `` `country` `` for a record of the country table, otherwise `or_else`
+fn name(kind: &Kind, or_else: &str) -> String {
+    match kind.table() {
+        Some(table) => format!("`{table}`"),
+        None => or_else.to_string(),
+    }
+}
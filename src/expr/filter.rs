@@ -0,0 +1,497 @@
+//! Filters transform a value before it is populated, e.g. `{{country | upper}}`
+//!
+//! See [`Filter`] for the built-in filters and [`Filters`] to register custom filters
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use anyhow::{anyhow, bail, Context as _};
+
+use super::Value;
+
+/// A filter is a named function applied to a value using a pipe `|`, filters may take arguments in parentheses.
+///
+/// Filters can be chained, each one is applied to the output of the filter before.
+///
+/// # Examples
+///
+/// ```bash
+/// {{country | upper}}
+/// {{name | replace("_", " ") | title}}
+/// {{code | pad(4, "0")}}
+/// {{desc | default("none")}}
+/// ```
+///
+/// # Built-in filters
+///
+/// | filter                        | description                                                            |
+/// | ----------------------------- | ---------------------------------------------------------------------- |
+/// | `upper`                       | converts to UPPERCASE                                                  |
+/// | `lower`                       | converts to lowercase                                                  |
+/// | `capitalize`                  | converts the first character to uppercase                             |
+/// | `title`                       | converts the first character of every word to uppercase               |
+/// | `trim`                        | removes leading and trailing whitespace                                |
+/// | `trim_start`                  | removes leading whitespace                                             |
+/// | `trim_end`                    | removes trailing whitespace                                            |
+/// | `pad(width, fill = " ")`      | adds `fill` to the start until the value is `width` characters long    |
+/// | `pad_end(width, fill = " ")`  | adds `fill` to the end until the value is `width` characters long      |
+/// | `replace(from, to)`           | replaces every `from` with `to`                                        |
+/// | `truncate(length, end = "")`  | shortens to `length` characters, adding `end` if anything was removed  |
+/// | `default(value)`              | uses `value` instead if the value is empty or only whitespace          |
+/// | `fixed(decimals)`             | formats a number with exactly `decimals` decimal places                |
+/// | `thousands(separator = ",")`  | separates the thousands of a number, e.g. `1,000,000`                  |
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub name: String,
+    pub args: Vec<Value>,
+}
+
+/// The names of every built-in [`Filter`]
+pub const BUILTIN_FILTERS: &[&str] = &[
+    "upper",
+    "lower",
+    "capitalize",
+    "title",
+    "trim",
+    "trim_start",
+    "trim_end",
+    "pad",
+    "pad_end",
+    "replace",
+    "truncate",
+    "default",
+    "fixed",
+    "thousands",
+];
+
+impl Filter {
+    pub fn new(name: &str) -> Self {
+        Filter {
+            name: name.into(),
+            args: Vec::new(),
+        }
+    }
+
+    pub fn with_args(name: &str, args: Vec<Value>) -> Self {
+        Filter {
+            name: name.into(),
+            args,
+        }
+    }
+
+    /// Applies this filter using only the built-in filters, see [`Filters::apply`] to include custom filters
+    pub fn apply(&self, value: String) -> anyhow::Result<String> {
+        builtin(&self.name, value, Args(&self.args)).with_context(|| {
+            format!(
+                "Failed filter `{}` with arguments {:?}",
+                self.name, self.args
+            )
+        })
+    }
+}
+
+/// A custom filter function, given the value to filter and the arguments the filter was called with.
+///
+/// The arguments are exported as [`FilterArg`](crate::FilterArg), e.g. `pad(4, "0")` is called with
+/// `[FilterArg::Uint(4), FilterArg::Text("0".into())]`
+pub type FilterFn = dyn Fn(&str, &[Value]) -> anyhow::Result<String> + Send + Sync;
+
+/// The [`Filter`]s available to a [`Template`](crate::Template): the built-in filters plus any custom filters registered here.
+///
+/// Custom filters are checked when a template is compiled, so using a filter that doesn't exist fails in
+/// [`Template::compile_with_filters`](crate::Template::compile_with_filters) rather than while populating.
+///
+/// # Examples
+///
+/// ```
+/// # use popvars::{FilterArg, Filters, Template};
+/// let mut filters = Filters::new();
+/// filters.register("game_id", |country, args| {
+///     let separator = match args {
+///         [FilterArg::Text(separator)] => separator.as_str(),
+///         _ => "_",
+///     };
+///     Ok(country.to_lowercase().replace(' ', separator))
+/// });
+///
+/// let template = Template::compile_with_filters("{{country | game_id(\"-\")}}", filters)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct Filters {
+    custom: HashMap<String, Arc<FilterFn>>,
+}
+
+impl Filters {
+    pub fn new() -> Self {
+        Filters::default()
+    }
+
+    /// Registers a custom filter, a custom filter with the same name as a built-in filter is used instead of the built-in filter
+    pub fn register<F>(&mut self, name: &str, filter: F) -> &mut Self
+    where
+        F: Fn(&str, &[Value]) -> anyhow::Result<String> + Send + Sync + 'static,
+    {
+        self.custom.insert(name.into(), Arc::new(filter));
+        self
+    }
+
+    /// Returns true if there is a built-in or custom filter named `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.custom.contains_key(name) || BUILTIN_FILTERS.contains(&name)
+    }
+
+    pub fn apply(&self, filter: &Filter, value: String) -> anyhow::Result<String> {
+        match self.custom.get(&filter.name) {
+            Some(custom) => custom(&value, &filter.args).with_context(|| {
+                format!(
+                    "Failed custom filter `{}` with arguments {:?}",
+                    filter.name, filter.args
+                )
+            }),
+            None => filter.apply(value),
+        }
+    }
+}
+
+impl fmt::Debug for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filters")
+            .field("custom", &self.custom.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn builtin(name: &str, value: String, args: Args) -> anyhow::Result<String> {
+    let filtered = match name {
+        "upper" => {
+            args.expect(0..=0)?;
+            value.to_uppercase()
+        }
+        "lower" => {
+            args.expect(0..=0)?;
+            value.to_lowercase()
+        }
+        "capitalize" => {
+            args.expect(0..=0)?;
+            capitalize(&value)
+        }
+        "title" => {
+            args.expect(0..=0)?;
+            value
+                .split_inclusive(char::is_whitespace)
+                .map(capitalize)
+                .collect()
+        }
+        "trim" => {
+            args.expect(0..=0)?;
+            value.trim().to_string()
+        }
+        "trim_start" => {
+            args.expect(0..=0)?;
+            value.trim_start().to_string()
+        }
+        "trim_end" => {
+            args.expect(0..=0)?;
+            value.trim_end().to_string()
+        }
+        "pad" => {
+            args.expect(1..=2)?;
+            let padding = padding(&value, args.usize(0)?, args.char_or(1, ' ')?);
+            format!("{padding}{value}")
+        }
+        "pad_end" => {
+            args.expect(1..=2)?;
+            let padding = padding(&value, args.usize(0)?, args.char_or(1, ' ')?);
+            format!("{value}{padding}")
+        }
+        "replace" => {
+            args.expect(2..=2)?;
+            value.replace(&args.text(0), &args.text(1))
+        }
+        "truncate" => {
+            args.expect(1..=2)?;
+            let length = args.usize(0)?;
+            if value.chars().count() > length {
+                let end = args.text_or(1, "");
+                value.chars().take(length).chain(end.chars()).collect()
+            } else {
+                value
+            }
+        }
+        "default" => {
+            args.expect(1..=1)?;
+            if value.trim().is_empty() {
+                args.text(0)
+            } else {
+                value
+            }
+        }
+        "fixed" => {
+            args.expect(1..=1)?;
+            let decimals = args.usize(0)?;
+            format!("{:.decimals$}", number(&value)?)
+        }
+        "thousands" => {
+            args.expect(0..=1)?;
+            thousands(&value, &args.text_or(0, ","))?
+        }
+        _ => bail!("Unknown filter `{name}`"),
+    };
+
+    Ok(filtered)
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn padding(value: &str, width: usize, fill: char) -> String {
+    let len = value.chars().count();
+    std::iter::repeat(fill)
+        .take(width.saturating_sub(len))
+        .collect()
+}
+
+/// Only plain decimals are numbers, exponents like `1e10`, `inf` and `NaN` are an error
+fn number(value: &str) -> anyhow::Result<f64> {
+    let value = value.trim();
+    decimal(value)?;
+    value
+        .parse::<f64>()
+        .with_context(|| format!("Expected `{value}` to be a number"))
+}
+
+/// Only plain decimals are separated, exponents like `1e10` and `inf` are an error rather than split in odd places
+fn thousands(value: &str, separator: &str) -> anyhow::Result<String> {
+    let (sign, integer, fraction) = decimal(value.trim())?;
+
+    let mut separated = String::new();
+    for (n, digit) in integer.chars().enumerate() {
+        if n > 0 && (integer.len() - n) % 3 == 0 {
+            separated.push_str(separator);
+        }
+        separated.push(digit);
+    }
+
+    Ok(format!("{sign}{separated}{fraction}"))
+}
+
+/// Splits a decimal written with digits, e.g. `-1234.5`, into its sign, its integer part and its fraction with the point
+fn decimal(value: &str) -> anyhow::Result<(&str, &str, &str)> {
+    let (sign, unsigned) = match value.strip_prefix(['-', '+']) {
+        Some(unsigned) => value.split_at(value.len() - unsigned.len()),
+        None => ("", value),
+    };
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(point) => unsigned.split_at(point),
+        None => (unsigned, ""),
+    };
+    let digits = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_digit());
+    if !digits(integer) || !(fraction.is_empty() || digits(&fraction[1..])) {
+        bail!("Expected `{value}` to be a number written with digits, e.g. `1234.5`");
+    }
+
+    Ok((sign, integer, fraction))
+}
+
+/// The arguments given to a [`Filter`]
+#[derive(Clone, Copy)]
+struct Args<'a>(&'a [Value]);
+
+impl Args<'_> {
+    fn expect(&self, range: std::ops::RangeInclusive<usize>) -> anyhow::Result<()> {
+        let len = self.0.len();
+        if !range.contains(&len) {
+            match (range.start(), range.end()) {
+                (start, end) if start == end => bail!("expected {start} arguments, found {len}"),
+                (start, end) => bail!("expected {start} to {end} arguments, found {len}"),
+            }
+        }
+        Ok(())
+    }
+
+    fn text(&self, n: usize) -> String {
+        self.0[n].to_string()
+    }
+
+    fn text_or(&self, n: usize, default: &str) -> String {
+        if n < self.0.len() {
+            self.text(n)
+        } else {
+            default.to_string()
+        }
+    }
+
+    fn usize(&self, n: usize) -> anyhow::Result<usize> {
+        match &self.0[n] {
+            Value::Uint(uint) => usize::try_from(*uint).map_err(|e| anyhow!(e)),
+            arg => bail!(
+                "expected argument {} to be a whole number, found {arg:?}",
+                n + 1
+            ),
+        }
+    }
+
+    fn char_or(&self, n: usize, default: char) -> anyhow::Result<char> {
+        if n >= self.0.len() {
+            return Ok(default);
+        }
+
+        let text = self.text(n);
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(fill), None) => Ok(fill),
+            _ => bail!(
+                "expected argument {} to be a single character, found `{text}`",
+                n + 1
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(filter: Filter, value: &str) -> anyhow::Result<String> {
+        filter.apply(value.to_string())
+    }
+
+    fn text(text: &str) -> Value {
+        Value::Text(text.into())
+    }
+
+    #[test]
+    fn test_case_filters() -> anyhow::Result<()> {
+        assert_eq!(apply(Filter::new("upper"), "Allies")?, "ALLIES");
+        assert_eq!(apply(Filter::new("lower"), "Allies")?, "allies");
+        assert_eq!(
+            apply(Filter::new("capitalize"), "soviet union")?,
+            "Soviet union"
+        );
+        assert_eq!(
+            apply(Filter::new("title"), "soviet  union")?,
+            "Soviet  Union"
+        );
+
+        assert!(apply(Filter::with_args("upper", vec![text("x")]), "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_trim_filters() -> anyhow::Result<()> {
+        assert_eq!(apply(Filter::new("trim"), " 42 ")?, "42");
+        assert_eq!(apply(Filter::new("trim_start"), " 42 ")?, "42 ");
+        assert_eq!(apply(Filter::new("trim_end"), " 42 ")?, " 42");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pad_filters() -> anyhow::Result<()> {
+        let pad = |args| Filter::with_args("pad", args);
+        assert_eq!(apply(pad(vec![Value::Uint(4), text("0")]), "45")?, "0045");
+        assert_eq!(apply(pad(vec![Value::Uint(4)]), "45")?, "  45");
+        assert_eq!(apply(pad(vec![Value::Uint(1)]), "45")?, "45");
+        assert_eq!(
+            apply(pad(vec![Value::Uint(4), Value::Uint(0)]), "45")?,
+            "0045"
+        );
+        assert!(apply(pad(vec![Value::Uint(4), text("ab")]), "45").is_err());
+        assert!(apply(pad(vec![Value::Int(-4)]), "45").is_err());
+
+        let pad_end = Filter::with_args("pad_end", vec![Value::Uint(4), text(".")]);
+        assert_eq!(apply(pad_end, "45")?, "45..");
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_truncate_default() -> anyhow::Result<()> {
+        let replace = Filter::with_args("replace", vec![text("_"), text(" ")]);
+        assert_eq!(apply(replace, "soviet_union")?, "soviet union");
+
+        let truncate = |args| Filter::with_args("truncate", args);
+        assert_eq!(apply(truncate(vec![Value::Uint(3)]), "Germany")?, "Ger");
+        assert_eq!(
+            apply(truncate(vec![Value::Uint(3), text("...")]), "Germany")?,
+            "Ger..."
+        );
+        assert_eq!(
+            apply(truncate(vec![Value::Uint(9), text("...")]), "Germany")?,
+            "Germany"
+        );
+
+        let default = Filter::with_args("default", vec![text("none")]);
+        assert_eq!(apply(default.clone(), " ")?, "none");
+        assert_eq!(apply(default, "some")?, "some");
+        Ok(())
+    }
+
+    #[test]
+    fn test_number_filters() -> anyhow::Result<()> {
+        let fixed = Filter::with_args("fixed", vec![Value::Uint(2)]);
+        assert_eq!(apply(fixed.clone(), "3.14159")?, "3.14");
+        assert_eq!(apply(fixed.clone(), "45")?, "45.00");
+        assert!(apply(fixed.clone(), "Germany").is_err());
+        for not_decimal in ["inf", "NaN", "1e3", "-infinity"] {
+            assert!(apply(fixed.clone(), not_decimal).is_err(), "{not_decimal}");
+        }
+
+        assert_eq!(apply(Filter::new("thousands"), "1234567")?, "1,234,567");
+        assert_eq!(apply(Filter::new("thousands"), "-1234.5")?, "-1,234.5");
+        assert_eq!(apply(Filter::new("thousands"), "123")?, "123");
+        assert!(apply(Filter::new("thousands"), "1e10").is_err());
+        assert!(apply(Filter::new("thousands"), "inf").is_err());
+        assert_eq!(
+            apply(Filter::with_args("thousands", vec![text(" ")]), "1000")?,
+            "1 000"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_filter() {
+        assert!(apply(Filter::new("shout"), "Allies").is_err());
+    }
+
+    #[test]
+    fn test_custom_filters() -> anyhow::Result<()> {
+        let mut filters = Filters::new();
+        filters
+            .register("shout", |value, _| Ok(format!("{value}!")))
+            .register("upper", |value, _| Ok(value.to_lowercase()))
+            .register("repeat", |value, args| match args {
+                [Value::Uint(n)] => Ok(value.repeat(*n as usize)),
+                _ => bail!("expected a number of repeats"),
+            });
+
+        assert!(filters.contains("shout"));
+        assert!(filters.contains("pad"));
+        assert!(!filters.contains("whisper"));
+
+        assert_eq!(
+            filters.apply(&Filter::new("shout"), "Allies".into())?,
+            "Allies!"
+        );
+        assert_eq!(
+            filters.apply(&Filter::new("upper"), "Allies".into())?,
+            "allies"
+        );
+        assert_eq!(
+            filters.apply(&Filter::new("lower"), "Allies".into())?,
+            "allies"
+        );
+        assert_eq!(
+            filters.apply(
+                &Filter::with_args("repeat", vec![Value::Uint(2)]),
+                "ab".into()
+            )?,
+            "abab"
+        );
+        assert!(filters.apply(&Filter::new("repeat"), "ab".into()).is_err());
+        Ok(())
+    }
+}
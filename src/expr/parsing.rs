@@ -2,98 +2,781 @@ use parsely::*;
 
 use super::*;
 
+/// The delimiters that open and close the expressions, blocks and comments of a template
+///
+/// The default is `{{ }}` for expressions, `{@ @}` for blocks and `{# #}` for comments,
+/// other delimiters help when the output itself uses lots of braces:
+///
+/// ```
+/// # use popvars::{Template, TemplateOptions, TemplateSyntax};
+/// let syntax = TemplateSyntax {
+///     expr_open: "<<".into(),
+///     expr_close: ">>".into(),
+///     block_open: "<%".into(),
+///     block_close: "%>".into(),
+///     comment_open: "<#".into(),
+///     comment_close: "#>".into(),
+/// };
+/// let options = TemplateOptions {
+///     syntax,
+///     ..TemplateOptions::default()
+/// };
+/// let template = Template::compile_with_options("<% if country = \"France\" %>{<<country>>}<% end if %>", options)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// A backslash escapes the first character of any delimiter, e.g. `\<<` is output as `<<`. Inside a tag a backslash
+/// escapes any punctuation, e.g. `<<a\>\>b>>` expands the field `a>>b`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateSyntax {
+    pub expr_open: String,
+    pub expr_close: String,
+    pub block_open: String,
+    pub block_close: String,
+    pub comment_open: String,
+    pub comment_close: String,
+}
+
+impl Default for TemplateSyntax {
+    fn default() -> Self {
+        TemplateSyntax {
+            expr_open: String::from("{{"),
+            expr_close: String::from("}}"),
+            block_open: String::from("{@"),
+            block_close: String::from("@}"),
+            comment_open: String::from("{#"),
+            comment_close: String::from("#}"),
+        }
+    }
+}
+
 pub fn template(input: &str) -> ParseResult<Vec<Node>> {
-    node().many(1..).then_end().parse(input).offset(input)
+    TemplateSyntax::default().template(input)
 }
 
-pub fn node() -> impl Parse<Output = Node> {
-    (block.map(Node::Block))
-        .or(expr.map(Node::Expr))
-        .or(text().map(Node::Text))
+pub fn node(input: &str) -> ParseResult<Node> {
+    TemplateSyntax::default().node(input)
 }
 
-// Some plain text between expressions
-pub fn text() -> impl Parse<Output = String> {
-    content_escape()
-        .many(1..)
-        .or_until("{@".or("{{"))
-        .collect::<String>()
+pub fn text(input: &str) -> ParseResult<'_, String> {
+    TemplateSyntax::default().text(input)
+}
+
+pub fn expr(input: &str) -> ParseResult<Expr> {
+    TemplateSyntax::default().expr(input)
+}
+
+pub fn block(input: &str) -> ParseResult<Block> {
+    TemplateSyntax::default().block(input)
+}
+
+pub fn block_expr(input: &str) -> ParseResult<BlockExpr> {
+    TemplateSyntax::default().block_expr(input)
+}
+
+impl TemplateSyntax {
+    /// Fails if any delimiter is empty, the opening delimiters can't be told apart or a closing delimiter could be
+    /// mistaken for the opening delimiter of another tag
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let delimiters = [
+            &self.expr_open,
+            &self.expr_close,
+            &self.block_open,
+            &self.block_close,
+            &self.comment_open,
+            &self.comment_close,
+        ];
+        if delimiters
+            .iter()
+            .any(|delimiter| delimiter.trim().is_empty())
+        {
+            anyhow::bail!("Template delimiters must not be empty or whitespace: {self:?}");
+        }
+
+        let opens = [&self.expr_open, &self.block_open, &self.comment_open];
+        for (n, a) in opens.iter().enumerate() {
+            for b in &opens[n + 1..] {
+                if a.starts_with(b.as_str()) || b.starts_with(a.as_str()) {
+                    anyhow::bail!(
+                        "The opening delimiters `{a}` and `{b}` must not start the same way"
+                    );
+                }
+            }
+        }
+
+        let pairs = [
+            (&self.expr_open, &self.expr_close),
+            (&self.block_open, &self.block_close),
+            (&self.comment_open, &self.comment_close),
+        ];
+        for (n, (_, close)) in pairs.iter().enumerate() {
+            for (m, (open, _)) in pairs.iter().enumerate() {
+                if n != m && (open.starts_with(close.as_str()) || close.starts_with(open.as_str()))
+                {
+                    anyhow::bail!(
+                        "The closing delimiter `{close}` and the opening delimiter `{open}` must not start the same way"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn template<'i>(&self, input: &'i str) -> ParseResult<'i, Vec<Node>> {
+        let mut nodes = Vec::new();
+        let mut remaining = input;
+
+        while !remaining.is_empty() || nodes.is_empty() {
+            let (mut node, after) = self.node(remaining).offset(input)?;
+            shift_spans(
+                std::slice::from_mut(&mut node),
+                input.len() - remaining.len(),
+            );
+            nodes.push(node);
+            remaining = after;
+        }
+
+        Ok((nodes, remaining))
+    }
+
+    /// The span of the innermost node that [`template`](TemplateSyntax::template) fails to parse,
+    /// from the start of the node to the end of its first closing delimiter, or the end of the line
+    ///
+    /// A block that is never closed fails at its opening tag
+    pub fn failed_at(&self, input: &str) -> Span {
+        let remaining = self.failing_node(input);
+
+        let line = remaining.lines().next().unwrap_or_default();
+        let end = [&self.expr_close, &self.block_close, &self.comment_close]
+            .iter()
+            .filter_map(|close| line.find(close.as_str()).map(|i| i + close.len()))
+            .min()
+            .unwrap_or(line.len());
+
+        let start = input.len() - remaining.len();
+        Span::new(start, start + end)
+    }
+
+    // the rest of `input` from the start of the innermost node that fails to parse
+    fn failing_node<'i>(&self, input: &'i str) -> &'i str {
+        let mut remaining = input;
+        while let Ok((_, after)) = self.node(remaining) {
+            if after.len() == remaining.len() {
+                return remaining;
+            }
+            remaining = after;
+        }
+
+        // a block with a valid opening tag fails somewhere in its content, or is never closed
+        let Ok((expr, mut content)) = self.block_expr(remaining) else {
+            return remaining;
+        };
+        let Some(tag) = expr.close() else {
+            return remaining;
+        };
+        let mut else_allowed = matches!(expr, BlockExpr::If(_));
+
+        while !content.is_empty() && self.close_block_expr(tag).lex(content).is_err() {
+            if self.open_else_tag().lex(content).is_ok() {
+                match self.else_tag(content) {
+                    // a final {@ else @} can't be followed by any more branches
+                    Ok((condition, after)) if else_allowed => {
+                        else_allowed = condition.is_some();
+                        content = after;
+                        continue;
+                    }
+                    _ => return content,
+                }
+            }
+
+            match self.node(content) {
+                Ok((_, after)) => content = after,
+                Err(_) => return self.failing_node(content),
+            }
+        }
+
+        remaining
+    }
+
+    // The spans of the node are relative to the start of `input`
+    pub fn node<'i>(&self, input: &'i str) -> ParseResult<'i, Node> {
+        self.raw(input)
+            .map(|(raw, remaining)| (Node::Text(raw), remaining))
+            .or_else(|_| {
+                self.block(input)
+                    .map(|(block, remaining)| (Node::Block(block), remaining))
+            })
+            .or_else(|_| {
+                self.expr(input)
+                    .map(|(expr, remaining)| (Node::Expr(expr), remaining))
+            })
+            .or_else(|_| {
+                self.text(input)
+                    .map(|(text, remaining)| (Node::Text(text), remaining))
+            })
+    }
+
+    // Some plain text between expressions, comments in the text are dropped
+    pub fn text<'i>(&self, input: &'i str) -> ParseResult<'i, String> {
+        let mut text = String::new();
+        let mut remaining = input;
+
+        loop {
+            if let Ok((_, after)) = self.comment(remaining) {
+                remaining = after;
+                continue;
+            }
+
+            let Ok((chunk, after)) = self
+                .content_escape()
+                .many(1..)
+                .or_until(
+                    self.block_open
+                        .as_str()
+                        .or(self.expr_open.as_str())
+                        .or(self.comment_open.as_str()),
+                )
+                .collect::<String>()
+                .parse(remaining)
+            else {
+                break;
+            };
+            text.push_str(&chunk);
+            remaining = after;
+        }
+
+        if remaining.len() == input.len() {
+            return Err(parsely::Error::no_match(input));
+        }
+
+        // {@- and {{- trim the whitespace before the tag
+        let trim_before = |open: &str| {
+            remaining
+                .strip_prefix(open)
+                .is_some_and(|tag| tag.starts_with('-'))
+        };
+        if trim_before(self.block_open.as_str()) || trim_before(self.expr_open.as_str()) {
+            text.truncate(text.trim_end().len());
+        }
+
+        Ok((text, remaining))
+    }
+
+    // {# a comment, which may span multiple lines #}
+    pub fn comment<'i>(&self, input: &'i str) -> ParseResult<'i, &'i str> {
+        let Some(content) = input.strip_prefix(self.comment_open.as_str()) else {
+            return Err(parsely::Error::no_match(input));
+        };
+        let Some(end) = content.find(self.comment_close.as_str()) else {
+            return Err(parsely::Error::no_match(input));
+        };
+
+        Ok((&content[..end], &content[end + self.comment_close.len()..]))
+    }
+
+    // {@ raw @} {{ and {@ are not interpreted in here {@ end raw @}
+    pub fn raw<'i>(&self, input: &'i str) -> ParseResult<'i, String> {
+        let (_, content) = self
+            .open_tag()
+            .then("raw")
+            .then(self.close_tag())
+            .lex(input)?;
+
+        // the first closing tag ends the raw block, even if it's in a comment
+        let mut search = content;
+        while let Some(start) = search.find(self.block_open.as_str()) {
+            let tag = &search[start..];
+            if let Ok((_, remaining)) = self.close_block_expr("raw").lex(tag) {
+                let mut raw = &content[..content.len() - tag.len()];
+
+                // {@- end raw @} trims the whitespace before it
+                if tag[self.block_open.len()..].starts_with('-') {
+                    raw = raw.trim_end();
+                }
+                return Ok((raw.to_string(), remaining));
+            }
+            search = &tag[self.block_open.len()..];
+        }
+
+        Err(parsely::Error::no_match(input))
+    }
+
+    // {@ or {@- i.e. the start of any block tag
+    fn open_tag(&self) -> impl Lex + '_ {
+        self.block_open
+            .as_str()
+            .then("-".optional())
+            .then(ws().optional())
+    }
+
+    // @} or -@} i.e. the end of any block tag, -@} trims the whitespace after the tag
+    fn close_tag(&self) -> impl Lex + '_ {
+        ws().optional().then(
+            "-".then(self.block_close.as_str())
+                .then(ws().many(..))
+                .or(self.block_close.as_str()),
+        )
+    }
+
+    pub fn expr<'i>(&self, input: &'i str) -> ParseResult<'i, Expr> {
+        let (inner, mut remaining) =
+            outer_brackets(&self.expr_open, &self.expr_close).lex(input)?;
+
+        // {{- trims the whitespace before the expr, which text() has already done
+        let inner = match inner.strip_prefix('-') {
+            Some(inner) => inner.trim_start(),
+            None => inner,
+        };
+
+        // -}} trims the whitespace after the expr
+        let inner = match inner.strip_suffix('-') {
+            Some(inner) => {
+                remaining = remaining.trim_start();
+                inner.trim_end()
+            }
+            None => inner,
+        };
+        let (expr, close) = tag_escape()
+            .lexing()
+            .many(1..)
+            .or_until(self.expr_close.as_str())
+            .lex(inner)
+            .offset(input)?;
+
+        let _ = end().lex(close)?;
+
+        let (kind, after) = expr_body(expand(), expr).offset(input)?;
+        let _ = end().lex(after)?;
+
+        let expr = Expr {
+            kind,
+            span: tag_span(input, remaining),
+        };
+        Ok((expr, remaining))
+    }
+
+    // {@ ___ ... @}  <content>  {@ end ___ @} i.e. the whole block including its content - **recursive via node()**
+    //    ^^^  these must be the same   ^^^
+    pub fn block<'i>(&self, input: &'i str) -> ParseResult<'i, Block> {
+        let (expr, remaining) = self.block_expr(input)?;
+        let span = tag_span(input, remaining);
+        let Some(tag) = expr.close() else {
+            return Ok((
+                Block {
+                    expr,
+                    nodes: Vec::new(),
+                    span,
+                    else_branches: Vec::new(),
+                },
+                remaining,
+            ));
+        };
+
+        let (nodes, mut remaining) = self.block_content(tag, input, remaining)?;
+
+        let mut else_branches = Vec::new();
+        if let BlockExpr::If(_) = expr {
+            while let Ok((condition, after)) = self.else_tag(remaining) {
+                let (nodes, after) = self.block_content(tag, input, after)?;
+                remaining = after;
+
+                // a final {@ else @} can't be followed by any more branches
+                let is_final = condition.is_none();
+                else_branches.push(ElseBranch { condition, nodes });
+                if is_final {
+                    break;
+                }
+            }
+        }
+
+        let (_, remaining) = self.close_block_expr(tag).lex(remaining)?;
+
+        let block = Block {
+            expr,
+            nodes,
+            span,
+            else_branches,
+        };
+
+        Ok((block, remaining))
+    }
+
+    // <content> i.e. the nodes inside a block, up to its closing tag or an else tag
+    //
+    // The spans of the nodes are relative to the start of `block`, the input the block was parsed from
+    fn block_content<'i>(
+        &self,
+        tag: &str,
+        block: &str,
+        input: &'i str,
+    ) -> ParseResult<'i, Vec<Node>> {
+        let mut nodes = Vec::new();
+        let mut remaining = input;
+
+        while self
+            .close_block_expr(tag)
+            .or(self.open_else_tag())
+            .lex(remaining)
+            .is_err()
+        {
+            let (mut node, after) = self.node(remaining)?;
+            shift_spans(
+                std::slice::from_mut(&mut node),
+                block.len() - remaining.len(),
+            );
+            nodes.push(node);
+            remaining = after;
+        }
+
+        Ok((nodes, remaining))
+    }
+
+    // {@ end name @} i.e. the closing tag of the block expr
+    pub fn close_block_expr<'a>(&'a self, name: &'a str) -> impl Lex + 'a {
+        self.open_tag()
+            .then("end ")
+            .then(token(name))
+            .then(self.close_tag())
+    }
+
+    // {@ else
+    fn open_else_tag(&self) -> impl Lex + '_ {
+        self.open_tag().then("else")
+    }
+
+    // {@ else @} or {@ else if team="Allies" @}
+    pub fn else_tag<'i>(&self, input: &'i str) -> ParseResult<'i, Option<Condition>> {
+        let (_, content) = self.open_else_tag().lex(input)?;
+        let (inner, remaining) = self.tag_content(content).offset(input)?;
+
+        let (condition, _) = if_tag()
+            .optional()
+            .then_end()
+            .parse(inner.trim_start())
+            .offset(input)?;
+
+        Ok((condition, remaining))
+    }
+
+    // {@ ___ ... @} i.e. the opening tag of the block expr
+    pub fn block_expr<'i>(&self, input: &'i str) -> ParseResult<'i, BlockExpr> {
+        let (_, content) = self.open_tag().lex(input)?;
+
+        let (tag, _) = until(" ").lex(content)?;
+
+        // It's difficult not to repeat ourselves here since for_in() and if_tag() are different types
+        // (we must return the same type from all match branches) and since Parse isn't object safe we can't use `dyn Parse<Output = BlockExpr>`
+        // so I've gone for slightly creative function usage to avoid repetition
+        match tag {
+            "for" => self.finish_block_tag(for_tag().map(BlockExpr::ForTag), content, input),
+            "if" => self.finish_block_tag(if_tag().map(BlockExpr::If), content, input),
+            "pop" => self.finish_block_tag(pop_tag.map(BlockExpr::Pop), content, input),
+            "set" => self.finish_block_tag(
+                "set".skip_then(assignment).map(BlockExpr::Set),
+                content,
+                input,
+            ),
+            "macro" => self.finish_block_tag(macro_tag.map(BlockExpr::Macro), content, input),
+            "call" => self.finish_block_tag(call_tag.map(BlockExpr::Call), content, input),
+            "import" => self.finish_block_tag(import_tag().map(BlockExpr::Import), content, input),
+            "with" => self.finish_block_tag(
+                "with".skip_then(assignment).map(BlockExpr::With),
+                content,
+                input,
+            ),
+            _ => Err(parsely::Error::no_match(content).offset(input)),
+        }
+    }
+
+    //  @}
+    pub fn finish_block_tag<'i>(
+        &self,
+        parser: impl Parse<Output = BlockExpr>,
+        content: &'i str,
+        input: &'i str,
+    ) -> ParseResult<'i, BlockExpr> {
+        // This bit is the same for every tag at the end to finish the block tag
+        let (inner, remaining) = self.tag_content(content).offset(input)?;
+
+        let (block_expr, _) = parser.then_end().parse(inner).offset(input)?;
+
+        Ok((block_expr, remaining))
+    }
+
+    // for c in country -@} i.e. the content of a block tag up to its closing tag, which is skipped over
+    //
+    // The content is cut out before it is parsed so that any closing delimiter ends it.
+    // Quoted values and escaped characters may contain the closing delimiter.
+    fn tag_content<'i>(&self, input: &'i str) -> ParseResult<'i, &'i str> {
+        let mut chars = input.char_indices();
+        let mut quote = None;
+
+        while let Some((i, c)) = chars.next() {
+            match (quote, c) {
+                (_, '\\') => {
+                    chars.next();
+                }
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '`') => quote = Some(c),
+                (None, _) => {
+                    if let Ok((_, remaining)) = self.close_tag().lex(&input[i..]) {
+                        return Ok((input[..i].trim_end(), remaining));
+                    }
+                }
+            }
+        }
+
+        Err(parsely::Error::no_match(input))
+    }
+
+    // \{ or \< etc. i.e. the escapes allowed in text, so that the delimiters can be output
+    fn content_escape(&self) -> EscapeSequence<6, Parsing> {
+        parsely::escape(
+            '\\',
+            [
+                ('\\', '\\'), //
+                first_char(&self.expr_open),
+                first_char(&self.expr_close),
+                first_char(&self.block_open),
+                first_char(&self.block_close),
+                first_char(&self.comment_open),
+            ],
+        )
+    }
 }
 
-pub fn outer_brackets(open: &'static str, close: &'static str) -> impl Lex {
-    let inner = expr_escape().lexing().many(1..).or_until(close);
+// the span of a tag parsed from the start of `input`, without any whitespace a trim marker removed after it
+fn tag_span(input: &str, remaining: &str) -> Span {
+    let tag = &input[..input.len() - remaining.len()];
+    Span::new(0, tag.trim_end().len())
+}
+
+// moves the spans of `nodes`, and all the nodes inside them, `offset` bytes later
+fn shift_spans(nodes: &mut [Node], offset: usize) {
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Expr(expr) => {
+                expr.span.start += offset;
+                expr.span.end += offset;
+            }
+            Node::Block(block) => {
+                block.span.start += offset;
+                block.span.end += offset;
+                shift_spans(&mut block.nodes, offset);
+                for branch in &mut block.else_branches {
+                    shift_spans(&mut branch.nodes, offset);
+                }
+            }
+        }
+    }
+}
+
+pub fn outer_brackets<'a>(open: &'a str, close: &'a str) -> impl Lex + 'a {
+    let inner = tag_escape().lexing().many(1..).or_until(close);
     (inner).pad_with(open, close)
 }
 
-pub fn expr(input: &str) -> ParseResult<Expr> {
-    let (inner, remaining) = outer_brackets("{{", "}}").lex(input)?;
-    let (expr, close) = expr_escape()
-        .lexing()
-        .many(1..)
-        .or_until("}}")
-        .lex(inner)
+// country.code | pad(4, "0") or sum(country.code) i.e. an expr without its braces
+fn expr_body(expand: ExpandParser, input: &str) -> ParseResult<'_, ExprKind> {
+    if let Some((function, args)) = aggregate_function(input) {
+        let (mut aggregate, after) = aggregate(function, args).offset(input)?;
+        let (filters, after) = filters(after).offset(input)?;
+
+        aggregate.filters = filters;
+        return Ok((ExprKind::Aggregate(aggregate), after));
+    }
+
+    let (mut expand, after) = expand.pad().parse(input).offset(input)?;
+    let (filters, after) = filters(after).offset(input)?;
+
+    let expr = if filters.is_empty() {
+        ExprKind::Expand(expand)
+    } else {
+        // whitespace is allowed between a field with spaces and the first pipe: {{field name | upper}}
+        expand.field.truncate(expand.field.trim_end().len());
+        ExprKind::Filtered(Filtered { expand, filters })
+    };
+    Ok((expr, after))
+}
+
+// enemy = country.team@Enemy | upper
+// side = "Allies"
+fn assignment(input: &str) -> ParseResult<'_, Assignment> {
+    let (name, after) = segment(tag_escape(), " =@")
+        .parse(input.trim_start())
         .offset(input)?;
 
-    let _ = end().lex(close)?;
+    let Some(after) = after.trim_start().strip_prefix('=') else {
+        return Err(parsely::Error::no_match(after).offset(input));
+    };
+    let after = after.trim_start();
 
-    let (expand, after) = expand().pad().parse(expr).offset(input)?;
-    let _ = end().lex(after)?;
+    // the same as the inside of {{ }}, so a filter may follow a field with or without spaces around its pipe
+    let (value, remaining) = assigned_value(expand(), after).offset(input)?;
 
-    let expr = Expr::Expand(expand);
-    Ok((expr, remaining))
+    Ok((Assignment { name, value }, remaining))
 }
 
-// {@ ___ ... @}  <content>  {@ end ___ @} i.e. the whole block including its content - **recursive via node()**
-//    ^^^  these must be the same   ^^^
-pub fn block(input: &str) -> ParseResult<Block> {
-    let (expr, remaining) = block_expr(input)?;
-    let tag = expr.close();
+// "Allies", 10, country@Enemy or country.team | upper i.e. the value of an assignment or a macro argument
+fn assigned_value(expand: ExpandParser, input: &str) -> ParseResult<'_, AssignedValue> {
+    if let Ok((value, remaining)) = value().parse(input) {
+        return Ok((AssignedValue::Value(value), remaining));
+    }
 
-    let (nodes, remaining) = node()
-        .many(..)
-        .or_until(close_block_expr(tag))
-        .then_skip(close_block_expr(tag))
-        .parse(remaining)?;
+    if let Ok((path, remaining)) = record_path(input) {
+        if remaining.trim_start().starts_with(['@', ',', ')']) {
+            return Ok((AssignedValue::Record(path), remaining));
+        }
+    }
 
-    Ok((Block { expr, nodes }, remaining))
+    let (expr, remaining) = expr_body(expand, input)?;
+    Ok((AssignedValue::Expr(expr), remaining))
 }
 
-// {@ end name @} i.e. the closing tag of the block expr
-pub fn close_block_expr(name: &str) -> impl Lex + '_ {
-    "{@".then(ws().optional())
-        .then("end ")
-        .then(token(name))
-        .then(ws().optional())
-        .then("@}")
+// macro morale_event(country, amount)
+pub fn macro_tag(input: &str) -> ParseResult<'_, MacroTag> {
+    let (name, remaining) = "macro"
+        .pad()
+        .skip_then(segment(tag_escape(), "( @"))
+        .parse(input)
+        .offset(input)?;
+
+    let Some(params) = remaining.trim_start().strip_prefix('(') else {
+        return Err(parsely::Error::no_match(remaining).offset(input));
+    };
+    let (params, remaining) = list(params, ')', |param| {
+        segment(tag_escape(), " ,)@").parse(param)
+    })
+    .offset(input)?;
+
+    Ok((MacroTag { name, params }, remaining))
 }
 
-// {@ ___ ... @} i.e. the opening tag of the block expr
-pub fn block_expr(input: &str) -> ParseResult<BlockExpr> {
-    let (_, content) = "{@".then(ws().optional()).lex(input)?;
+// call morale_event(country, "10")
+pub fn call_tag(input: &str) -> ParseResult<'_, CallTag> {
+    let (name, remaining) = "call"
+        .pad()
+        .skip_then(segment(tag_escape(), "( @"))
+        .parse(input)
+        .offset(input)?;
 
-    let (tag, _) = until(" ").lex(content)?;
+    let Some(args) = remaining.trim_start().strip_prefix('(') else {
+        return Err(parsely::Error::no_match(remaining).offset(input));
+    };
+    let (args, remaining) =
+        list(args, ')', |arg| assigned_value(expand_arg(), arg)).offset(input)?;
 
-    // It's difficult not to repeat ourselves here since for_in() and if_tag() are different types
-    // (we must return the same type from all match branches) and since Parse isn't object safe we can't use `dyn Parse<Output = BlockExpr>`
-    // so I've gone for slightly creative function usage to avoid repetition
-    match tag {
-        "for" => finish_block_tag(for_tag().map(BlockExpr::ForTag), content, input),
-        "if" => finish_block_tag(if_tag().map(BlockExpr::If), content, input),
-        _ => Err(parsely::Error::no_match(content).offset(input)),
+    Ok((CallTag { name, args }, remaining))
+}
+
+// import macros/events.txt
+pub fn import_tag() -> impl Parse<Output = String> {
+    "import".pad().skip_then(segment(tag_escape(), " @"))
+}
+
+// country@Enemy or country.team@Enemy i.e. lookups ending with an explicit index, which find a record rather than a value
+fn record_path(input: &str) -> ParseResult<'_, Vec<Lookup>> {
+    let mut path = Vec::new();
+    let mut remaining = input;
+
+    loop {
+        let (found, after) = lookup(remaining).offset(input)?;
+        path.push(found);
+
+        match after.strip_prefix('.') {
+            Some(after) => remaining = after,
+            None => {
+                remaining = after;
+                break;
+            }
+        }
+    }
+
+    match path.last() {
+        Some(Lookup { index: Some(_), .. }) => Ok((path, remaining)),
+        _ => Err(parsely::Error::no_match(input)),
     }
 }
 
-//  @}
-pub fn finish_block_tag<'i>(
-    parser: impl Parse<Output = BlockExpr>,
-    content: &'i str,
-    input: &'i str,
-) -> ParseResult<'i, BlockExpr> {
-    parser
-        // This bit is the same for every tag at the end to finish the block tag
-        .then_skip(ws().optional().then("@}"))
-        .parse(content)
-        .offset(input)
+// count( i.e. the name of an aggregate function and its opening parenthesis
+fn aggregate_function(input: &str) -> Option<(AggregateFn, &str)> {
+    [
+        AggregateFn::Count,
+        AggregateFn::Sum,
+        AggregateFn::Min,
+        AggregateFn::Max,
+        AggregateFn::Avg,
+    ]
+    .into_iter()
+    .find_map(|function| {
+        let after = input.trim_start().strip_prefix(function.name())?;
+        let args = after.trim_start().strip_prefix('(')?;
+        Some((function, args))
+    })
+}
+
+// country.code where team = "Allies") i.e. the arguments of an aggregate function up to and including the closing parenthesis
+fn aggregate(function: AggregateFn, input: &str) -> ParseResult<'_, Aggregate> {
+    let (mut expand, after) = expand_strict().parse(input.trim_start()).offset(input)?;
+
+    // the first part names the table, the rest is expanded relative to each of its records
+    let (table_name, value) = match expand.path.is_empty() {
+        true => (expand.field, None),
+        false => (expand.path.remove(0).table_name, Some(expand)),
+    };
+
+    let (where_clause, after) = match where_clause(after.trim_start()) {
+        Ok((where_clause, after)) => (Some(where_clause), after),
+        Err(_) => (None, after),
+    };
+
+    let Some(after) = after.trim_start().strip_prefix(')') else {
+        return Err(parsely::Error::no_match(after).offset(input));
+    };
+
+    let aggregate = Aggregate {
+        function,
+        table_name,
+        value,
+        where_clause,
+        filters: Vec::new(),
+    };
+    Ok((aggregate, after))
+}
+
+// | upper | pad(4, "0") i.e. every filter following an expand
+fn filters(input: &str) -> ParseResult<'_, Vec<Filter>> {
+    let mut filters = Vec::new();
+    let mut remaining = input;
+
+    while let Some(after) = remaining.trim_start().strip_prefix('|') {
+        let (filter, after) = filter(after.trim_start()).offset(input)?;
+        filters.push(filter);
+        remaining = after;
+    }
+
+    Ok((filters, remaining.trim_start()))
+}
+
+// pad(4, "0")
+fn filter(input: &str) -> ParseResult<'_, Filter> {
+    let (name, remaining) = segment(tag_escape(), "(|) ").parse(input)?;
+
+    match remaining.trim_start().strip_prefix('(') {
+        Some(args) => {
+            let (args, remaining) = value_list(args, ')').offset(input)?;
+            Ok((Filter { name, args }, remaining))
+        }
+        None => Ok((
+            Filter {
+                name,
+                args: Vec::new(),
+            },
+            remaining,
+        )),
+    }
 }
 
 // for `allied_country` in `country` where team="Allies"
@@ -101,21 +784,162 @@ pub fn for_tag() -> impl Parse<Output = ForTag> {
     "for"
         .pad()
         .skip_then(other_clause)
-        .then(segment(expr_escape(), " ").then_skip(" "))
+        .then(segment(tag_escape(), " ").then_skip(" "))
         .then_skip("in".pad())
-        .then(lookup)
+        .then(for_source)
         .then(where_clause.pad().optional())
-        .map(|(((other_clause, ctx), lookup), where_clause)| ForTag {
-            new_context_name: ctx,
-            lookup,
-            other_clause,
-            where_clause,
-        })
+        .then(group_by.optional())
+        .then(loop_order)
+        .map(
+            |(((((other_clause, ctx), source), where_clause), group_by), order)| ForTag {
+                new_context_name: ctx,
+                source,
+                other_clause,
+                where_clause,
+                group_by,
+                order,
+            },
+        )
+}
+
+// group by team
+fn group_by(input: &str) -> ParseResult<'_, Expand> {
+    let Some(after) = keyword("group", input).and_then(|after| keyword("by", after)) else {
+        return Err(parsely::Error::no_match(input));
+    };
+
+    expand_strict().parse(after).offset(input)
+}
+
+// `country`, `[1, 2, 3]` or `team.records`
+fn for_source(input: &str) -> ParseResult<'_, ForSource> {
+    match input.strip_prefix('[') {
+        Some(list) => {
+            let (values, remaining) = value_list(list, ']').offset(input)?;
+            Ok((ForSource::Values(values), remaining))
+        }
+        None => {
+            let (lookup, remaining) = lookup.parse(input).offset(input)?;
+
+            // team.records i.e. the records of a group from a surrounding group by loop
+            match remaining.strip_prefix(".records") {
+                Some(after) if lookup.index.is_none() => {
+                    Ok((ForSource::GroupRecords(lookup.table_name), after))
+                }
+                _ => Ok((ForSource::Table(lookup), remaining)),
+            }
+        }
+    }
+}
+
+// order by code desc reverse offset 2 limit 5
+//
+// every clause is optional and they may be written in any order, but each at most once
+fn loop_order(input: &str) -> ParseResult<'_, LoopOrder> {
+    let mut order = LoopOrder::default();
+    let mut remaining = input;
+
+    loop {
+        let duplicate = || Err(parsely::Error::no_match(remaining).offset(input));
+
+        if let Some(after) = keyword("order", remaining).and_then(|after| keyword("by", after)) {
+            if order.order_by.is_some() {
+                return duplicate();
+            }
+            let (expand, after) = expand_strict().parse(after).offset(input)?;
+            let (descending, after) = match (keyword("desc", after), keyword("asc", after)) {
+                (Some(after), _) => (true, after),
+                (None, Some(after)) => (false, after),
+                (None, None) => (false, after),
+            };
+            order.order_by = Some(OrderBy { expand, descending });
+            remaining = after;
+        } else if let Some(after) = keyword("reverse", remaining) {
+            if order.reverse {
+                return duplicate();
+            }
+            order.reverse = true;
+            remaining = after;
+        } else if let Some(after) = keyword("offset", remaining) {
+            if order.offset.is_some() {
+                return duplicate();
+            }
+            let (offset, after) = count(after).offset(input)?;
+            order.offset = Some(offset);
+            remaining = after;
+        } else if let Some(after) = keyword("limit", remaining) {
+            if order.limit.is_some() {
+                return duplicate();
+            }
+            let (limit, after) = count(after).offset(input)?;
+            order.limit = Some(limit);
+            remaining = after;
+        } else {
+            return Ok((order, remaining));
+        }
+    }
+}
+
+// 5
+fn count(input: &str) -> ParseResult<'_, usize> {
+    let len = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (digits, remaining) = input.split_at(len);
+
+    match digits.parse() {
+        Ok(count) => Ok((count, remaining)),
+        Err(_) => Err(parsely::Error::no_match(input)),
+    }
 }
 
 // if team="Allies"
-pub fn if_tag() -> impl Parse<Output = Comparison> {
-    "if".pad().skip_then(comparison)
+pub fn if_tag() -> impl Parse<Output = Condition> {
+    "if".pad().skip_then(condition)
+}
+
+// pop macros/header.txt with country.team as team, "Allies" as side
+pub fn pop_tag(input: &str) -> ParseResult<'_, PopTag> {
+    let (path, remaining) = "pop"
+        .pad()
+        .skip_then(segment(tag_escape(), " @"))
+        .parse(input)
+        .offset(input)?;
+
+    let Ok((_, mut remaining)) = "with".pad().lex(remaining) else {
+        let pop_tag = PopTag {
+            path,
+            with_clause: Vec::new(),
+        };
+        return Ok((pop_tag, remaining));
+    };
+
+    let mut with_clause = Vec::new();
+    loop {
+        let (binding, after) = pop_binding(remaining).offset(input)?;
+        with_clause.push(binding);
+
+        match ",".pad().lex(after) {
+            Ok((_, next)) => remaining = next,
+            Err(_) => {
+                remaining = after;
+                break;
+            }
+        }
+    }
+
+    Ok((PopTag { path, with_clause }, remaining))
+}
+
+// country.team as team
+// "Allies" as side
+fn pop_binding(input: &str) -> ParseResult<'_, PopBinding> {
+    (string('"').map(PopValue::Text))
+        .or(expand_strict().map(PopValue::Expand))
+        .then_skip("as".pad())
+        .then(segment(tag_escape(), " ,@"))
+        .map(|(value, new_field)| PopBinding { value, new_field })
+        .parse(input)
 }
 
 fn other_clause(input: &str) -> ParseResult<'_, bool> {
@@ -127,18 +951,138 @@ fn other_clause(input: &str) -> ParseResult<'_, bool> {
 }
 
 fn value() -> impl Parse<Output = Value> {
-    (string('"').map(Value::Text))
-        .or(uint::<u64>().map(Value::Uint))
-        .or(int::<i64>().map(Value::Int))
-        .or(float::<f64>().map(Value::Float))
+    (string('"').map(Value::Text)).or(number)
+}
+
+// 42, -7 or 2.5
+fn number(input: &str) -> ParseResult<'_, Value> {
+    let len = input
+        .char_indices()
+        .find(|&(n, c)| !(c.is_ascii_digit() || c == '.' || (n == 0 && (c == '-' || c == '+'))))
+        .map_or(input.len(), |(n, _)| n);
+    let (number, remaining) = input.split_at(len);
+
+    let value = if let Ok(uint) = number.parse::<u64>() {
+        Value::Uint(uint)
+    } else if let Ok(int) = number.parse::<i64>() {
+        Value::Int(int)
+    } else if let Ok(float) = number.parse::<f64>() {
+        Value::Float(float)
+    } else {
+        return Err(parsely::Error::no_match(input));
+    };
+
+    Ok((value, remaining))
+}
+
+// "a", 2, 3.5) i.e. comma separated values up to and including the closing bracket
+fn value_list(input: &str, close: char) -> ParseResult<'_, Vec<Value>> {
+    list(input, close, |item| value().parse(item))
+}
+
+// a, b, c) i.e. comma separated items up to and including the closing bracket
+fn list<'i, T>(
+    input: &'i str,
+    close: char,
+    item: impl Fn(&'i str) -> ParseResult<'i, T>,
+) -> ParseResult<'i, Vec<T>> {
+    let mut items = Vec::new();
+
+    let mut remaining = input.trim_start();
+    if let Some(after) = remaining.strip_prefix(close) {
+        return Ok((items, after));
+    }
+
+    loop {
+        let (parsed, after) = item(remaining).offset(input)?;
+        items.push(parsed);
+
+        let after = after.trim_start();
+        if let Some(after) = after.strip_prefix(',') {
+            remaining = after.trim_start();
+        } else if let Some(after) = after.strip_prefix(close) {
+            return Ok((items, after));
+        } else {
+            return Err(parsely::Error::no_match(after).offset(input));
+        }
+    }
 }
 
 // where team="Allies"
-fn where_clause(input: &str) -> ParseResult<Comparison> {
-    "where".pad().skip_then(comparison).parse(input)
+fn where_clause(input: &str) -> ParseResult<Condition> {
+    "where".pad().skip_then(condition).parse(input)
+}
+
+// team="Allies" or not (code > 50 and code < 100)
+pub fn condition(input: &str) -> ParseResult<Condition> {
+    let (first, mut remaining) = and_condition(input)?;
+
+    let mut conditions = vec![first];
+    while let Some(after) = keyword("or", remaining) {
+        let (condition, after) = and_condition(after).offset(input)?;
+        conditions.push(condition);
+        remaining = after;
+    }
+
+    if conditions.len() == 1 {
+        Ok((conditions.remove(0), remaining))
+    } else {
+        Ok((Condition::Or(conditions), remaining))
+    }
+}
+
+// team="Allies" and not code > 50
+fn and_condition(input: &str) -> ParseResult<Condition> {
+    let (first, mut remaining) = not_condition(input)?;
+
+    let mut conditions = vec![first];
+    while let Some(after) = keyword("and", remaining) {
+        let (condition, after) = not_condition(after).offset(input)?;
+        conditions.push(condition);
+        remaining = after;
+    }
+
+    if conditions.len() == 1 {
+        Ok((conditions.remove(0), remaining))
+    } else {
+        Ok((Condition::And(conditions), remaining))
+    }
+}
+
+// not code > 50
+fn not_condition(input: &str) -> ParseResult<Condition> {
+    if let Some(after) = keyword("not", input) {
+        let (condition, remaining) = not_condition(after).offset(input)?;
+        return Ok((Condition::Not(Box::new(condition)), remaining));
+    }
+
+    // (team="Allies" or team="Axis")
+    if let Some(after) = input.trim_start().strip_prefix('(') {
+        let (condition, remaining) = condition(after.trim_start()).offset(input)?;
+        let Some(remaining) = remaining.trim_start().strip_prefix(')') else {
+            return Err(parsely::Error::no_match(remaining).offset(input));
+        };
+        return Ok((condition, remaining));
+    }
+
+    let (comparison, remaining) = comparison(input.trim_start()).offset(input)?;
+    Ok((Condition::Comparison(comparison), remaining))
+}
+
+/// Matches a keyword such as `and` surrounded by whitespace, returning what comes after it.
+///
+/// The keyword may instead be followed by an opening parenthesis, e.g. `not(`, or the end of the input
+fn keyword<'i>(word: &str, input: &'i str) -> Option<&'i str> {
+    let after = input.trim_start().strip_prefix(word)?;
+
+    if after.is_empty() || after.starts_with(char::is_whitespace) || after.starts_with('(') {
+        Some(after.trim_start())
+    } else {
+        None
+    }
 }
 
-// team="Allies"
+// team="Allies" or team=enemy.team
 fn comparison(input: &str) -> ParseResult<Comparison> {
     let cmp = switch([
         ("!=", Comparator::NotEqual),
@@ -149,9 +1093,11 @@ fn comparison(input: &str) -> ParseResult<Comparison> {
         ("=", Comparator::Equal),
     ]);
 
+    let operand = (value().map(Operand::Value)).or(expand_strict().map(Operand::Expand));
+
     let (((expand, comparator), value), remaining) = expand_strict()
         .then(cmp.pad())
-        .then(value())
+        .then(operand)
         .parse(input)
         .offset(input)?;
 
@@ -164,8 +1110,14 @@ fn comparison(input: &str) -> ParseResult<Comparison> {
     Ok((where_clause, remaining))
 }
 
+/// Parses an [`Expand`] from the content of a tag, which [`TemplateSyntax`] has already cut out, so the delimiters
+/// never end a field and the terminators are only the expression syntax, e.g. `@` is an explicit index
 pub struct ExpandParser {
-    strict: bool,
+    /// characters that terminate the field after one or more lookups
+    path_terminators: &'static str,
+
+    /// characters that terminate a field without any lookups
+    field_terminators: &'static str,
 }
 
 impl Parse for ExpandParser {
@@ -175,49 +1127,61 @@ impl Parse for ExpandParser {
         lookup
             .then_skip('.')
             .many(1..=100)
-            .then(segment(expr_escape(), "{@ .}"))
+            .then(segment(tag_escape(), self.path_terminators))
             .then_skip(ws().many(..))
             .map(|(path, field)| Expand { path, field })
-            .or(if self.strict {
-                // terminate on space
-                segment(expr_escape(), "{@ .}")
-            } else {
-                // allow spaces
-                segment(expr_escape(), "{@.}")
-            }
-            .then_skip(ws().many(..))
-            .map(|field| Expand {
-                path: Vec::new(),
-                field,
-            }))
+            .or(segment(tag_escape(), self.field_terminators)
+                .then_skip(ws().many(..))
+                .map(|field| Expand {
+                    path: Vec::new(),
+                    field,
+                }))
             .parse(input)
     }
 }
 
 // a strict version of expand parser that does not allow significant whitespace without backticks {{field name with spaces}}
+//
+// terminates on a closing parenthesis too, so comparisons can be grouped
 fn expand_strict() -> ExpandParser {
-    ExpandParser { strict: true }
+    ExpandParser {
+        path_terminators: "@ .)",
+        field_terminators: "@ .)",
+    }
 }
 
+// allows spaces in a field without lookups, terminates on a pipe which begins a filter
 fn expand() -> ExpandParser {
-    ExpandParser { strict: false }
+    ExpandParser {
+        path_terminators: "@ .|",
+        field_terminators: "@.|",
+    }
+}
+
+// a strict version of expand parser for macro call arguments, terminates on a comma which begins the next argument
+fn expand_arg() -> ExpandParser {
+    ExpandParser {
+        path_terminators: "@ .),|",
+        field_terminators: "@ .),|",
+    }
 }
 
 fn lookup(input: &str) -> ParseResult<'_, Lookup> {
-    let ((table_name, index), remaining) = segment(expr_escape(), "{@ .}")
+    let ((table_name, index), remaining) = segment(tag_escape(), "@ .")
         .then(explicit_index().optional())
         .parse(input)?;
 
     Ok((Lookup { index, table_name }, remaining))
 }
 
+// terminates on a comma or closing parenthesis too, so it can end a macro argument
 fn explicit_index() -> impl Parse<Output = String> {
-    '@'.skip_then(segment(expr_escape(), "@ ."))
+    '@'.skip_then(segment(tag_escape(), "@ .,)"))
 }
 
 fn string(quote: char) -> impl Parse<Output = String> {
     quote.skip_then(
-        str_escape(quote)
+        tag_escape()
             .many(1..)
             .or_until(quote)
             .collect::<String>()
@@ -235,43 +1199,20 @@ fn segment(
         .collect::<String>())
 }
 
-fn expr_escape() -> EscapeSequence<5, Parsing> {
-    parsely::escape(
-        '\\',
-        [
-            ('\\', '\\'), //
-            ('@', '@'),
-            ('{', '{'),
-            ('}', '}'),
-            ('.', '.'),
-        ],
-    )
+// \@, \. or \> etc. i.e. a backslash escapes any punctuation inside a tag, whatever the delimiters around it are
+fn tag_escape() -> EscapeSequence<32, Parsing> {
+    parsely::escape('\\', PUNCTUATION.map(|c| (c, c)))
 }
 
-fn content_escape() -> EscapeSequence<4, Parsing> {
-    parsely::escape(
-        '\\',
-        [
-            ('\\', '\\'), //
-            ('@', '@'),
-            ('{', '{'),
-            ('}', '}'),
-        ],
-    )
-}
+const PUNCTUATION: [char; 32] = [
+    '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/', ':', ';', '<', '=',
+    '>', '?', '@', '[', '\\', ']', '^', '_', '`', '{', '|', '}', '~',
+];
 
-fn str_escape(quote: char) -> EscapeSequence<6, Parsing> {
-    parsely::escape(
-        '\\',
-        [
-            ('\\', '\\'), //
-            ('@', '@'),
-            ('{', '{'),
-            ('}', '}'),
-            ('.', '.'),
-            (quote, quote),
-        ],
-    )
+// e.g. ('<', '<') for `<<`, so that `\<` escapes it
+fn first_char(delimiter: &str) -> (char, char) {
+    let first = delimiter.chars().next().unwrap_or('\\');
+    (first, first)
 }
 
 #[cfg(test)]
@@ -316,67 +1257,562 @@ mod tests {
         );
     }
 
+    // the parsed nodes with their spans cleared, to compare them with nodes built without spans
+    fn unspanned<'i>(parsed: ParseResult<'i, Vec<Node>>) -> (Vec<Node>, &'i str) {
+        let (mut nodes, remaining) = parsed.unwrap();
+        clear_spans(&mut nodes);
+        (nodes, remaining)
+    }
+
+    // an expr node without a span, to compare with parsed nodes after their spans are cleared
+    fn expr_node(input: &str) -> Node {
+        Node::Expr(Expr::new(Expr::from_str(input).unwrap().kind))
+    }
+
+    // parses an expr without its span
+    fn expr_kind(input: &str) -> ParseResult<'_, ExprKind> {
+        expr(input).map(|(expr, remaining)| (expr.kind, remaining))
+    }
+
+    fn clear_spans(nodes: &mut [Node]) {
+        for node in nodes {
+            match node {
+                Node::Text(_) => {}
+                Node::Expr(expr) => expr.span = Span::default(),
+                Node::Block(block) => {
+                    block.span = Span::default();
+                    clear_spans(&mut block.nodes);
+                    for branch in &mut block.else_branches {
+                        clear_spans(&mut branch.nodes);
+                    }
+                }
+            }
+        }
+    }
+    #[test]
+    fn test_expr_aggregate() {
+        assert_parse_match(
+            expr_kind,
+            r#"{{count(country where team = "Allies")}}"#,
+            ExprKind::Aggregate(Aggregate {
+                function: AggregateFn::Count,
+                table_name: "country".into(),
+                value: None,
+                where_clause: Some(Condition::Comparison(Comparison::new(
+                    Expand::new("team"),
+                    Comparator::Equal,
+                    Value::Text("Allies".into()),
+                ))),
+                filters: vec![],
+            }),
+        );
+
+        assert_parse_match(
+            expr_kind,
+            r#"{{ avg( country.team.rank ) | fixed(1) }}"#,
+            ExprKind::Aggregate(Aggregate {
+                function: AggregateFn::Avg,
+                table_name: "country".into(),
+                value: Some(Expand::with_lookup("rank", Lookup::direct("team"))),
+                where_clause: None,
+                filters: vec![Filter::with_args("fixed", vec![Value::Uint(1)])],
+            }),
+        );
+
+        // an aggregate name on its own is still a field
+        assert_parse_match(
+            expr_kind,
+            "{{count}}",
+            ExprKind::Expand(Expand::new("count")),
+        );
+
+        assert!(expr("{{sum(country.code}}").is_err());
+        assert!(expr("{{sum(country.code where)}}").is_err());
+    }
+
     #[test]
     fn test_block_text() {
         let block = "{@ for `field` in `table_name` @}loop content{@ end for @}";
-        let (nodes, remaining) = template(block).unwrap();
+        let (nodes, remaining) = unspanned(template(block));
 
         assert_eq!(remaining, "");
         assert_eq!(
             nodes[0],
             Node::Block(Block {
+                span: Span::default(),
                 expr: BlockExpr::ForTag(ForTag {
                     new_context_name: "field".into(),
-                    lookup: Lookup::direct("table_name"),
+                    source: ForSource::Table(Lookup::direct("table_name")),
                     where_clause: None,
                     other_clause: false,
+                    group_by: None,
+                    order: LoopOrder::default(),
                 }),
                 nodes: vec![Node::Text("loop content".into())],
+                else_branches: vec![],
             })
         );
         assert_eq!(nodes.len(), 1);
     }
 
+    #[test]
+    fn test_trim_markers() {
+        let input = "a \n{@- if x = \"y\" -@}\n  {{- x -}}  \n{@ end if -@} \n b";
+        let (nodes, remaining) = unspanned(template(input));
+
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("a".into()),
+                Node::Block(Block {
+                    span: Span::default(),
+                    expr: BlockExpr::If(Condition::Comparison(Comparison::new(
+                        Expand::new("x"),
+                        Comparator::Equal,
+                        Value::Text("y".into()),
+                    ))),
+                    nodes: vec![Node::Expr(Expr::new(ExprKind::Expand(Expand::new("x"))))],
+                    else_branches: vec![],
+                }),
+                Node::Text("b".into()),
+            ]
+        );
+
+        // the markers only trim their own side of the tag
+        let (nodes, _) = unspanned(template(" {{-x}} "));
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("".into()),
+                Node::Expr(Expr::new(ExprKind::Expand(Expand::new("x")))),
+                Node::Text(" ".into()),
+            ]
+        );
+        let (nodes, _) = unspanned(template(" {{x-}} "));
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text(" ".into()),
+                Node::Expr(Expr::new(ExprKind::Expand(Expand::new("x")))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_parse_match(text, "a{# note #}b", String::from("ab"));
+        assert_parse_match(text, "a{# over\nlines #}", String::from("a"));
+        assert_parse_match(
+            text,
+            r"a\{# not a comment #}",
+            String::from("a{# not a comment #}"),
+        );
+        assert!(template("a{# never closed").is_err());
+
+        // a comment only template still parses, to no output
+        let (nodes, _) = unspanned(template("{# {{nothing}} {@ here @} #}"));
+        assert_eq!(nodes, vec![Node::Text("".into())]);
+
+        // the closing tag in the comment doesn't close the block
+        let input = "{@ if x = \"y\" @}{# {@ end if @} #}z{@ end if @}";
+        let (nodes, remaining) = unspanned(template(input));
+        assert_eq!(remaining, "");
+        let Node::Block(block) = &nodes[0] else {
+            panic!("expected a block");
+        };
+        assert_eq!(block.nodes, vec![Node::Text("z".into())]);
+    }
+
+    #[test]
+    fn test_raw() {
+        let input = "a{@ raw @}{{x}} {@ for @} \\{ {# #}{@ end raw @}b";
+        let (nodes, remaining) = unspanned(template(input));
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("a".into()),
+                Node::Text(r"{{x}} {@ for @} \{ {# #}".into()),
+                Node::Text("b".into()),
+            ]
+        );
+
+        assert_parse_match(
+            node,
+            "{@- raw -@}\n x \n{@- end raw @}",
+            Node::Text("x".into()),
+        );
+        assert!(template("{@ raw @}{{x}}").is_err());
+    }
+
+    #[test]
+    fn test_custom_syntax() {
+        let syntax = TemplateSyntax {
+            expr_open: "<<".into(),
+            expr_close: ">>".into(),
+            block_open: "<%".into(),
+            block_close: "%>".into(),
+            comment_open: "<#".into(),
+            comment_close: "#>".into(),
+        };
+        syntax.validate().unwrap();
+
+        let input = "{{a}} {@ b @} <%- if x = \"y\" -%> <<x | upper>> <% end if %><% raw %><<x>><% end raw %>";
+        let (nodes, remaining) = unspanned(syntax.template(input));
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("{{a}} {@ b @}".into()),
+                Node::Block(Block {
+                    span: Span::default(),
+                    expr: BlockExpr::If(Condition::Comparison(Comparison::new(
+                        Expand::new("x"),
+                        Comparator::Equal,
+                        Value::Text("y".into()),
+                    ))),
+                    nodes: vec![expr_node("{{x | upper}}"), Node::Text(" ".into())],
+                    else_branches: vec![],
+                }),
+                Node::Text("<<x>>".into()),
+            ]
+        );
+
+        // the closing delimiter ends a block tag even without a space before it
+        let input = r"<# note #>\<<x>> <%if x = y%>z<%end if%>";
+        let (nodes, remaining) = unspanned(syntax.template(input));
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("<<x>> ".into()),
+                Node::Block(Block {
+                    span: Span::default(),
+                    expr: BlockExpr::If(Condition::Comparison(Comparison::new(
+                        Expand::new("x"),
+                        Comparator::Equal,
+                        Expand::new("y"),
+                    ))),
+                    nodes: vec![Node::Text("z".into())],
+                    else_branches: vec![],
+                }),
+            ]
+        );
+
+        // quoted values may contain the closing delimiter
+        let (block_expr, _) = syntax.block_expr(r#"<% set s = "%>" %>"#).unwrap();
+        assert_eq!(
+            block_expr,
+            BlockExpr::Set(Assignment {
+                name: "s".into(),
+                value: AssignedValue::Value(Value::Text("%>".into())),
+            })
+        );
+
+        let clash = TemplateSyntax {
+            block_open: "{{".into(),
+            ..TemplateSyntax::default()
+        };
+        assert!(clash.validate().is_err());
+        let clash = TemplateSyntax {
+            comment_open: "{".into(),
+            ..TemplateSyntax::default()
+        };
+        assert!(clash.validate().is_err());
+        let clash = TemplateSyntax {
+            expr_open: "%>".into(),
+            expr_close: "<%".into(),
+            ..syntax.clone()
+        };
+        assert!(clash.validate().is_err());
+
+        // a backslash escapes the closing delimiter inside a tag too
+        let (expr, _) = syntax.expr(r"<<a\>\>b>>").unwrap();
+        assert_eq!(expr.kind, ExprKind::Expand(Expand::new("a>>b")));
+    }
+
     #[test]
     fn test_block_without_backticks() {
         let block = "{@ for field in table_name @}loop content{@ end for @}";
-        let (nodes, remaining) = template(block).unwrap();
+        let (nodes, remaining) = unspanned(template(block));
 
         assert_eq!(remaining, "");
         assert_eq!(
             nodes[0],
             Node::Block(Block {
+                span: Span::default(),
                 expr: BlockExpr::ForTag(ForTag {
                     new_context_name: "field".into(),
-                    lookup: Lookup::direct("table_name"),
+                    source: ForSource::Table(Lookup::direct("table_name")),
                     where_clause: None,
                     other_clause: false,
+                    group_by: None,
+                    order: LoopOrder::default(),
                 }),
                 nodes: vec![Node::Text("loop content".into())],
+                else_branches: vec![],
             })
         );
         assert_eq!(nodes.len(), 1);
     }
 
+    #[test]
+    fn test_block_value_list() {
+        let block = r#"{@ for n in [1, -2, 2.5, "four"] @}{{n}}{@ end for @}"#;
+        let (nodes, remaining) = unspanned(template(block));
+
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes[0],
+            Node::Block(Block {
+                span: Span::default(),
+                expr: BlockExpr::ForTag(ForTag {
+                    new_context_name: "n".into(),
+                    source: ForSource::Values(vec![
+                        Value::Uint(1),
+                        Value::Int(-2),
+                        Value::Float(2.5),
+                        Value::Text("four".into()),
+                    ]),
+                    where_clause: None,
+                    other_clause: false,
+                    group_by: None,
+                    order: LoopOrder::default(),
+                }),
+                nodes: vec![Node::Expr(Expr::new(ExprKind::Expand(Expand::new("n"))))],
+                else_branches: vec![],
+            })
+        );
+
+        assert!(template("{@ for n in [1, 2 @}{{n}}{@ end for @}").is_err());
+    }
+
+    #[test]
+    fn test_for_tag_order() -> anyhow::Result<()> {
+        let for_tag: ForTag =
+            r#"for c in country where team = "Allies" order by code desc limit 5 @}"#.parse()?;
+        assert_eq!(
+            for_tag.order,
+            LoopOrder {
+                order_by: Some(OrderBy {
+                    expand: Expand::new("code"),
+                    descending: true,
+                }),
+                reverse: false,
+                offset: None,
+                limit: Some(5),
+            }
+        );
+        assert!(for_tag.where_clause.is_some());
+
+        let for_tag: ForTag = "for c in country limit 2 reverse offset 1 @}".parse()?;
+        assert_eq!(
+            for_tag.order,
+            LoopOrder {
+                order_by: None,
+                reverse: true,
+                offset: Some(1),
+                limit: Some(2),
+            }
+        );
+
+        let for_tag: ForTag = "for c in country order by team.name asc @}".parse()?;
+        assert_eq!(
+            for_tag.order.order_by,
+            Some(OrderBy {
+                expand: Expand::with_lookup("name", Lookup::direct("team")),
+                descending: false,
+            })
+        );
+
+        assert!(template("{@ for c in country limit 1 limit 2 @}{@ end for @}").is_err());
+        assert!(template("{@ for c in country limit many @}{@ end for @}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_tag_group_by() -> anyhow::Result<()> {
+        let for_tag: ForTag =
+            r#"for side in country where code > 40 group by team order by team @}"#.parse()?;
+        assert_eq!(for_tag.group_by, Some(Expand::new("team")));
+        assert!(for_tag.where_clause.is_some());
+        assert!(for_tag.order.order_by.is_some());
+        assert!(matches!(for_tag.ctx_idx()?, ContextIndex::GroupBy { .. }));
+
+        let for_tag: ForTag = "for c in side.records limit 2 @}".parse()?;
+        assert_eq!(for_tag.source, ForSource::GroupRecords("side".into()));
+        assert!(for_tag.group_by.is_none());
+
+        let for_tag: ForTag = "for c in side.records group by team @}".parse()?;
+        assert!(for_tag.ctx_idx().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_and_with() {
+        assert_parse_match(
+            block_expr,
+            "{@ set enemy = country@Enemy.team @}",
+            BlockExpr::Set(Assignment {
+                name: "enemy".into(),
+                value: AssignedValue::Expr(ExprKind::Expand(Expand::with_lookup(
+                    "team",
+                    Lookup::indirect("country", "Enemy"),
+                ))),
+            }),
+        );
+
+        assert_parse_match(
+            block_expr,
+            "{@ set enemy = country.team@Enemy @}",
+            BlockExpr::Set(Assignment {
+                name: "enemy".into(),
+                value: AssignedValue::Record(vec![
+                    Lookup::direct("country"),
+                    Lookup::indirect("team", "Enemy"),
+                ]),
+            }),
+        );
+
+        assert_parse_match(
+            block_expr,
+            r#"{@ set side="Allies" @}"#,
+            BlockExpr::Set(Assignment {
+                name: "side".into(),
+                value: AssignedValue::Value(Value::Text("Allies".into())),
+            }),
+        );
+
+        assert_parse_match(
+            block_expr,
+            "{@ set code = country.code | pad(4) @}",
+            BlockExpr::Set(Assignment {
+                name: "code".into(),
+                value: AssignedValue::Expr(ExprKind::Filtered(Filtered {
+                    expand: Expand::with_lookup("code", Lookup::direct("country")),
+                    filters: vec![Filter::with_args("pad", vec![Value::Uint(4)])],
+                })),
+            }),
+        );
+
+        for input in [
+            "{@ set name = name|upper @}",
+            "{@ set name = name | upper @}",
+        ] {
+            assert_parse_match(
+                block_expr,
+                input,
+                BlockExpr::Set(Assignment {
+                    name: "name".into(),
+                    value: AssignedValue::Expr(ExprKind::Filtered(Filtered {
+                        expand: Expand::new("name"),
+                        filters: vec![Filter::new("upper")],
+                    })),
+                }),
+            );
+        }
+
+        let (nodes, remaining) = unspanned(template("{@ with n = 10 @}{{n}}{@ end with @}"));
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes,
+            vec![Node::Block(Block {
+                span: Span::default(),
+                expr: BlockExpr::With(Assignment {
+                    name: "n".into(),
+                    value: AssignedValue::Value(Value::Uint(10)),
+                }),
+                nodes: vec![Node::Expr(Expr::new(ExprKind::Expand(Expand::new("n"))))],
+                else_branches: vec![],
+            })]
+        );
+
+        assert!(block_expr("{@ set side @}").is_err());
+        assert!(block_expr("{@ set side = @}").is_err());
+    }
+
+    #[test]
+    fn test_macro_call_and_import() {
+        assert_parse_match(
+            block_expr,
+            "{@ macro morale_event(country, amount) @}",
+            BlockExpr::Macro(MacroTag {
+                name: "morale_event".into(),
+                params: vec!["country".into(), "amount".into()],
+            }),
+        );
+
+        assert_parse_match(
+            block_expr,
+            r#"{@ call morale_event(country@Enemy, "10", country.code | pad(4, "0"), c) @}"#,
+            BlockExpr::Call(CallTag {
+                name: "morale_event".into(),
+                args: vec![
+                    AssignedValue::Record(vec![Lookup::indirect("country", "Enemy")]),
+                    AssignedValue::Value(Value::Text("10".into())),
+                    AssignedValue::Expr(ExprKind::Filtered(Filtered {
+                        expand: Expand::with_lookup("code", Lookup::direct("country")),
+                        filters: vec![Filter::with_args(
+                            "pad",
+                            vec![Value::Uint(4), Value::Text("0".into())],
+                        )],
+                    })),
+                    AssignedValue::Expr(ExprKind::Expand(Expand::new("c"))),
+                ],
+            }),
+        );
+
+        assert_parse_match(
+            block_expr,
+            "{@ call header() @}",
+            BlockExpr::Call(CallTag {
+                name: "header".into(),
+                args: vec![],
+            }),
+        );
+
+        assert_parse_match(
+            block_expr,
+            "{@ import macros/events.txt @}",
+            BlockExpr::Import("macros/events.txt".into()),
+        );
+
+        let (nodes, remaining) = unspanned(template(
+            "{@ macro shout(text) @}{{text | upper}}{@ end macro @}",
+        ));
+        assert_eq!(remaining, "");
+        assert_eq!(nodes.len(), 1);
+
+        assert!(block_expr("{@ call morale_event(country @}").is_err());
+        assert!(block_expr("{@ macro morale_event @}").is_err());
+    }
+
     #[test]
     fn test_block_expr() {
         let block = "{@ for `field` in `table_name` @}{{loop expr}}{@ end for @}";
-        let (nodes, remaining) = template(block).unwrap();
+        let (nodes, remaining) = unspanned(template(block));
 
         assert_eq!(remaining, "");
         assert_eq!(
             nodes[0],
             Node::Block(Block {
+                span: Span::default(),
                 expr: BlockExpr::ForTag(ForTag {
                     new_context_name: "field".into(),
-                    lookup: Lookup::direct("table_name"),
+                    source: ForSource::Table(Lookup::direct("table_name")),
                     where_clause: None,
                     other_clause: false,
+                    group_by: None,
+                    order: LoopOrder::default(),
                 }),
-                nodes: vec![Node::Expr(Expr::Expand(Expand {
+                nodes: vec![Node::Expr(Expr::new(ExprKind::Expand(Expand {
                     field: "loop expr".into(),
                     path: vec![]
-                }))],
+                })))],
+                else_branches: vec![],
             })
         );
         assert_eq!(nodes.len(), 1);
@@ -389,28 +1825,32 @@ mod tests {
             {@ for `field` in `table_name` @}loop content{{loop expr}}{@ end for @}
             text content after loop
         "};
-        let (nodes, remaining) = template(block).unwrap();
+        let (nodes, remaining) = unspanned(template(block));
 
         assert_eq!(remaining, "");
         assert_eq!(nodes[0], Node::from_text("text content."));
-        assert_eq!(nodes[1], Node::Expr(Expr::from_str("{{expr}}").unwrap()));
+        assert_eq!(nodes[1], expr_node("{{expr}}"));
         assert_eq!(nodes[2], Node::from_text("\n"));
         assert_eq!(
             nodes[3],
             Node::Block(Block {
+                span: Span::default(),
                 expr: BlockExpr::ForTag(ForTag {
                     new_context_name: "field".to_string(),
-                    lookup: Lookup::direct("table_name"),
+                    source: ForSource::Table(Lookup::direct("table_name")),
                     where_clause: None,
                     other_clause: false,
+                    group_by: None,
+                    order: LoopOrder::default(),
                 }),
                 nodes: vec![
                     Node::from_text("loop content"),
-                    Node::Expr(Expr::Expand(Expand {
+                    Node::Expr(Expr::new(ExprKind::Expand(Expand {
                         field: "loop expr".to_string(),
                         path: vec![],
-                    }))
-                ]
+                    })))
+                ],
+                else_branches: vec![],
             })
         );
         assert_eq!(nodes[4], Node::from_text("\ntext content after loop\n"));
@@ -420,19 +1860,23 @@ mod tests {
     #[test]
     fn test_for_other() {
         let block = "{@ for other `field` in `table_name` @}inner{@ end for @}";
-        let (nodes, remaining) = template(block).unwrap();
+        let (nodes, remaining) = unspanned(template(block));
 
         assert_eq!(remaining, "");
         assert_eq!(
             nodes[0],
             Node::Block(Block {
+                span: Span::default(),
                 expr: BlockExpr::ForTag(ForTag {
                     new_context_name: "field".into(),
-                    lookup: Lookup::direct("table_name"),
+                    source: ForSource::Table(Lookup::direct("table_name")),
                     where_clause: None,
                     other_clause: true,
+                    group_by: None,
+                    order: LoopOrder::default(),
                 }),
                 nodes: vec![Node::from_text("inner")],
+                else_branches: vec![],
             })
         );
         assert_eq!(nodes.len(), 1);
@@ -449,33 +1893,34 @@ mod tests {
                 {{outer.$id}}={{outer.code}}
             {@ end for @}
         "};
-        let (nodes, remaining) = template(block).unwrap();
+        let (nodes, remaining) = unspanned(template(block));
 
         assert_eq!(remaining, "");
         assert_eq!(nodes[0], Node::from_text("foo is in vars: "));
-        assert_eq!(nodes[1], Node::Expr(Expr::from_str("{{foo}}").unwrap()));
+        assert_eq!(nodes[1], expr_node("{{foo}}"));
         assert_eq!(nodes[2], Node::from_text("\n\nouter_table is in defs: "));
-        assert_eq!(
-            nodes[3],
-            Node::Expr(Expr::from_str("{{outer_table.code}}").unwrap())
-        );
+        assert_eq!(nodes[3], expr_node("{{outer_table.code}}"));
         assert_eq!(nodes[4], Node::from_text("\n"));
         assert_eq!(
             nodes[5],
             Node::Block(Block {
+                span: Span::default(),
                 expr: BlockExpr::ForTag(ForTag {
                     new_context_name: "outer".to_string(),
-                    lookup: Lookup::direct("outer_table"),
+                    source: ForSource::Table(Lookup::direct("outer_table")),
                     where_clause: None,
                     other_clause: false,
+                    group_by: None,
+                    order: LoopOrder::default(),
                 }),
                 nodes: vec![
                     Node::from_text("\n    `outer.code` now refers to the same table as `outer_table.code`\n    "),
-                    Node::Expr(Expr::from_str("{{outer.$id}}").unwrap()),
+                    expr_node("{{outer.$id}}"),
                     Node::from_text("="),
-                    Node::Expr(Expr::from_str("{{outer.code}}").unwrap()),
+                    expr_node("{{outer.code}}"),
                     Node::from_text("\n"),
-                ]
+                ],
+                else_branches: vec![],
             })
         );
         assert_eq!(nodes[6], Node::from_text("\n"));
@@ -541,19 +1986,83 @@ mod tests {
     #[test]
     fn test_node_with_remainder() {
         assert_parse_match(
-            node(),
+            node,
             "{{loop expr}}{@ end for @}",
-            Node::Expr(Expr::Expand(Expand {
-                field: "loop expr".into(),
-                path: vec![],
-            })),
+            Node::Expr(Expr {
+                kind: ExprKind::Expand(Expand {
+                    field: "loop expr".into(),
+                    path: vec![],
+                }),
+                span: Span::new(0, 13),
+            }),
         );
     }
 
+    #[test]
+    fn test_failed_at() {
+        let syntax = TemplateSyntax::default();
+        let failed_at = |input: &str| {
+            let span = syntax.failed_at(input);
+            input[span.start..span.end].to_string()
+        };
+
+        assert_eq!(failed_at("a {{sum(x}} b"), "{{sum(x}}");
+        assert_eq!(
+            failed_at(
+                "{@ for c in country @}\n  {@ if c = 1 @}{{sum(x}}{@ end if @}\n{@ end for @}"
+            ),
+            "{{sum(x}}"
+        );
+        assert_eq!(
+            failed_at("{@ for c in country @}{{c}} and more"),
+            "{@ for c in country @}"
+        );
+        assert_eq!(
+            failed_at("{@ for c in country @}{@ else @}{@ end for @}"),
+            "{@ else @}"
+        );
+        assert_eq!(
+            failed_at("{@ if x = 1 @}a{@ else @}b{@ else if y = 2 @}c{@ end if @}"),
+            "{@ else if y = 2 @}"
+        );
+        assert_eq!(
+            failed_at("{@ for c in country @}{{c}}{@ end if @}"),
+            "{@ end if @}"
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let input = "a {{x}}\n{@ for c in country -@}\n  {{ c.code }}{@ end for @}";
+        let (nodes, _) = template(input).unwrap();
+
+        let Node::Expr(Expr { span, .. }) = &nodes[1] else {
+            panic!("expected an expr: {nodes:?}");
+        };
+        assert_eq!(&input[span.start..span.end], "{{x}}");
+        assert_eq!(span.line_column(input), (1, 3));
+
+        let Node::Block(block) = &nodes[3] else {
+            panic!("expected a block: {nodes:?}");
+        };
+        assert_eq!(
+            &input[block.span.start..block.span.end],
+            "{@ for c in country -@}"
+        );
+        assert_eq!(block.span.line_column(input), (2, 1));
+
+        let Node::Expr(Expr { span, .. }) = &block.nodes[0] else {
+            panic!("expected an expr: {:?}", block.nodes);
+        };
+        assert_eq!(&input[span.start..span.end], "{{ c.code }}");
+        assert_eq!(span.line_column(input), (3, 3));
+    }
+
     #[test]
     fn test_close_block_expr() {
+        let syntax = TemplateSyntax::default();
         assert_lex_match(
-            close_block_expr("for"),
+            syntax.close_block_expr("for"),
             expr.parse("{{loop expr}}{@ end for @}").unwrap().1,
             "{@ end for @}",
         );
@@ -597,7 +2106,8 @@ mod tests {
         assert_parse_match(expand(), r"`field` <", Expand::new("field"));
         assert_parse_match(expand(), r"field <", Expand::new("field <")); // this is a valid field/table name even though it looks like a where clause
 
-        assert_parse_fails(expand(), "{{field}}", "{} are special chars, need escaping");
+        // the tag is already cut out by its delimiters, so braces are part of the field
+        assert_parse_match(expand(), "{{field}}", Expand::new("{{field}}"));
     }
 
     #[test]
@@ -657,32 +2167,36 @@ mod tests {
 
     #[test]
     fn test_expr() {
-        assert_parse_match(expr, "{{country}}", Expr::Expand(Expand::new("country")));
         assert_parse_match(
-            expr,
+            expr_kind,
+            "{{country}}",
+            ExprKind::Expand(Expand::new("country")),
+        );
+        assert_parse_match(
+            expr_kind,
             "{{country.code}}",
-            Expr::Expand(Expand::with_lookup("code", Lookup::direct("country"))),
+            ExprKind::Expand(Expand::with_lookup("code", Lookup::direct("country"))),
         );
         assert_parse_match(
-            expr,
+            expr_kind,
             "{{country@`Enemy Country`.code}}",
-            Expr::Expand(Expand::with_lookup(
+            ExprKind::Expand(Expand::with_lookup(
                 "code",
                 Lookup::indirect("country", "Enemy Country"),
             )),
         );
         assert_parse_match(
-            expr,
+            expr_kind,
             "{{country.team.code}}",
-            Expr::Expand(Expand::with_nested_lookups(
+            ExprKind::Expand(Expand::with_nested_lookups(
                 "code",
                 vec![Lookup::direct("country"), Lookup::direct("team")],
             )),
         );
         assert_parse_match(
-            expr,
+            expr_kind,
             "{{country@`Enemy Country`.team.code}}",
-            Expr::Expand(Expand::with_nested_lookups(
+            ExprKind::Expand(Expand::with_nested_lookups(
                 "code",
                 vec![
                     Lookup::indirect("country", "Enemy Country"),
@@ -692,9 +2206,9 @@ mod tests {
         );
 
         assert_parse_match(
-            expr,
+            expr_kind,
             "{{ country@`Enemy Country`.team.code }}",
-            Expr::Expand(Expand::with_nested_lookups(
+            ExprKind::Expand(Expand::with_nested_lookups(
                 "code",
                 vec![
                     Lookup::indirect("country", "Enemy Country"),
@@ -711,7 +2225,7 @@ mod tests {
         );
 
         assert_parse_match(
-            segment(expr_escape(), "@ ."),
+            segment(tag_escape(), "@ ."),
             r"\.field",
             ".field".to_string(),
         );
@@ -723,25 +2237,67 @@ mod tests {
         );
 
         assert_parse_match(
-            expr,
+            expr_kind,
             r"{{table@dr\.index.code}}",
-            Expr::Expand(Expand::with_lookup(
+            ExprKind::Expand(Expand::with_lookup(
                 "code",
                 Lookup::indirect("table", "dr.index"),
             )),
         );
     }
 
+    #[test]
+    fn test_expr_filters() {
+        assert_parse_match(
+            expr_kind,
+            "{{country | upper}}",
+            ExprKind::Filtered(Filtered {
+                expand: Expand::new("country"),
+                filters: vec![Filter::new("upper")],
+            }),
+        );
+
+        assert_parse_match(
+            expr_kind,
+            r#"{{ country name|replace("_", " ") | pad(4, "0") | truncate(2.5) }}"#,
+            ExprKind::Filtered(Filtered {
+                expand: Expand::new("country name"),
+                filters: vec![
+                    Filter::with_args(
+                        "replace",
+                        vec![Value::Text("_".into()), Value::Text(" ".into())],
+                    ),
+                    Filter::with_args("pad", vec![Value::Uint(4), Value::Text("0".into())]),
+                    Filter::with_args("truncate", vec![Value::Float(2.5)]),
+                ],
+            }),
+        );
+
+        assert_parse_match(
+            expr_kind,
+            "{{country@`Enemy Country`.team | lower}}",
+            ExprKind::Filtered(Filtered {
+                expand: Expand::with_lookup("team", Lookup::indirect("country", "Enemy Country")),
+                filters: vec![Filter::new("lower")],
+            }),
+        );
+
+        assert_parse_match(expr_kind, r"{{a\|b}}", ExprKind::Expand(Expand::new("a|b")));
+
+        assert_parse_fails(expr, "{{country | pad(4, }}", "unclosed filter arguments");
+        assert_parse_fails(expr, "{{country | }}", "missing filter name");
+    }
+
     #[test]
     fn test_where_clause() {
         assert_parse_match(
             where_clause,
             r#"where team <= "Allies""#,
-            Comparison::new(
+            Condition::Comparison(Comparison::new(
                 Expand::new("team"),
                 Comparator::LessThanOrEqual,
                 Value::Text("Allies".into()),
-            ),
+            )),
         );
     }
 
@@ -750,12 +2306,104 @@ mod tests {
         assert_parse_match(
             if_tag(),
             r#"if team <= "Allies""#,
-            Comparison::new(
+            Condition::Comparison(Comparison::new(
                 Expand::new("team"),
                 Comparator::LessThanOrEqual,
                 Value::Text("Allies".into()),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_condition() {
+        let team = |value: &str| {
+            Condition::Comparison(Comparison::new(
+                Expand::new("team"),
+                Comparator::Equal,
+                Value::Text(value.into()),
+            ))
+        };
+        let code_above = |value: u64| {
+            Condition::Comparison(Comparison::new(
+                Expand::new("code"),
+                Comparator::GreaterThan,
+                Value::Uint(value),
+            ))
+        };
+
+        assert_parse_match(condition, r#"team = "Allies""#, team("Allies"));
+
+        assert_parse_match(
+            condition,
+            r#"team = "Allies" and code > 50"#,
+            Condition::And(vec![team("Allies"), code_above(50)]),
+        );
+
+        // and binds tighter than or
+        assert_parse_match(
+            condition,
+            r#"team = "Allies" or team = "Axis" and code > 50"#,
+            Condition::Or(vec![
+                team("Allies"),
+                Condition::And(vec![team("Axis"), code_above(50)]),
+            ]),
+        );
+
+        assert_parse_match(
+            condition,
+            r#"not (team = "Allies" or team = "Axis") and not code > 50"#,
+            Condition::And(vec![
+                Condition::Not(Box::new(Condition::Or(vec![team("Allies"), team("Axis")]))),
+                Condition::Not(Box::new(code_above(50))),
+            ]),
+        );
+
+        assert_parse_match(condition, r#"((team = "Allies"))"#, team("Allies"));
+
+        // conditions are written back the way they are parsed
+        for input in [
+            r#"team = "Allies" or team = "Axis" and code > 50"#,
+            r#"not (team = "Allies" or team = "Axis") and not code > 50"#,
+            r#"(team = "Allies" or code <= 1.5) and country@Enemy.team != enemy.team"#,
+            r#"country@`Enemy Country`.team = "a \"b\"" and `field name` != "c\\d""#,
+        ] {
+            let (parsed, _) = condition(input).unwrap();
+            assert_eq!(parsed.to_string(), input);
+        }
+
+        assert_parse_fails(
+            condition,
+            r#"(team = "Allies""#,
+            "missing closing parenthesis",
+        );
+        assert_parse_fails(
+            condition,
+            r#"team = "Allies" and"#,
+            "and is missing its right hand side",
+        );
+    }
+
+    #[test]
+    fn test_comparison_expand_operand() {
+        assert_parse_match(
+            comparison,
+            "country.team != enemy.team",
+            Comparison::new(
+                Expand::with_lookup("team", Lookup::direct("country")),
+                Comparator::NotEqual,
+                Expand::with_lookup("team", Lookup::direct("enemy")),
             ),
         );
+
+        assert_parse_match(
+            condition,
+            r#"(country = `Enemy Country`)"#,
+            Condition::Comparison(Comparison::new(
+                Expand::new("country"),
+                Comparator::Equal,
+                Expand::new("Enemy Country"),
+            )),
+        );
     }
 
     #[test]
@@ -771,6 +2419,107 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pop_tag() {
+        assert_parse_match(
+            pop_tag,
+            "pop macros/header.txt",
+            PopTag {
+                path: "macros/header.txt".into(),
+                with_clause: vec![],
+            },
+        );
+
+        assert_parse_match(
+            pop_tag,
+            r#"pop `my macros/header.txt` with country.team as team, "say \"hi\" \\o/" as greeting"#,
+            PopTag {
+                path: "my macros/header.txt".into(),
+                with_clause: vec![
+                    PopBinding {
+                        value: PopValue::Expand(Expand::with_lookup(
+                            "team",
+                            Lookup::direct("country"),
+                        )),
+                        new_field: "team".into(),
+                    },
+                    PopBinding {
+                        value: PopValue::Text(r#"say "hi" \o/"#.into()),
+                        new_field: "greeting".into(),
+                    },
+                ],
+            },
+        );
+    }
+
+    #[test]
+    fn test_pop_block() {
+        let (nodes, remaining) = unspanned(template("before{@ pop header.txt @}after"));
+
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes,
+            vec![
+                Node::from_text("before"),
+                Node::Block(Block {
+                    span: Span::default(),
+                    expr: BlockExpr::Pop(PopTag {
+                        path: "header.txt".into(),
+                        with_clause: vec![],
+                    }),
+                    nodes: vec![],
+                    else_branches: vec![],
+                }),
+                Node::from_text("after"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_if_else_block() {
+        let block = r#"{@ if team = "Allies" @}A{@ else if team = "Axis" @}B{@else@}C{@ end if @}"#;
+        let (nodes, remaining) = unspanned(template(block));
+
+        assert_eq!(remaining, "");
+        assert_eq!(
+            nodes,
+            vec![Node::Block(Block {
+                span: Span::default(),
+                expr: BlockExpr::If(Condition::Comparison(Comparison::new(
+                    Expand::new("team"),
+                    Comparator::Equal,
+                    Value::Text("Allies".into()),
+                ))),
+                nodes: vec![Node::from_text("A")],
+                else_branches: vec![
+                    ElseBranch {
+                        condition: Some(Condition::Comparison(Comparison::new(
+                            Expand::new("team"),
+                            Comparator::Equal,
+                            Value::Text("Axis".into()),
+                        ))),
+                        nodes: vec![Node::from_text("B")],
+                    },
+                    ElseBranch {
+                        condition: None,
+                        nodes: vec![Node::from_text("C")],
+                    },
+                ],
+            })]
+        );
+
+        assert_parse_fails(
+            template,
+            r#"{@ if team = "Allies" @}A{@ else @}B{@ else @}C{@ end if @}"#,
+            "else must be the final branch",
+        );
+        assert_parse_fails(
+            template,
+            "{@ for c in country @}A{@ else @}B{@ end for @}",
+            "only if blocks have else branches",
+        );
+    }
+
     #[test]
     fn test_comparison_lookup_no_backticks() {
         assert_parse_match(
@@ -2,26 +2,112 @@
 
 mod definition;
 mod expr;
-mod table;
+pub mod table;
 mod template;
 
+use std::path::{Component, Path, PathBuf};
+
 use anyhow::Context as AnyhowContext;
 pub use definition::Definition;
-pub use expr::{Context, Expand, Expr, Lookup};
-pub use table::{Record, Table};
-pub use template::Template;
+pub use expr::{
+    Aggregate, AggregateFn, Context, Expand, Expr, ExprKind, Filter, FilterFn, Filtered, Filters,
+    Lookup, TemplateSyntax, Value as FilterArg,
+};
+pub use table::{ColumnType, Record, Table};
+pub use template::{Template, TemplateOptions};
 
 pub fn pop(input: &str, def: Definition) -> anyhow::Result<Vec<String>> {
+    pop_with_options(input, def, TemplateOptions::default())
+}
+
+/// Like [`pop`] but compiles the template with the given [`TemplateOptions`]
+pub fn pop_with_options(
+    input: &str,
+    def: Definition,
+    options: TemplateOptions,
+) -> anyhow::Result<Vec<String>> {
     let mut output = Vec::new();
 
-    let template = Template::compile(input)?;
+    let template = Template::compile_with_options(input, options)?;
 
     for (n, var) in def.vars.iter().enumerate() {
         let popped = template.pop(var, &def).with_context(|| {
-            format!("Error while populating template with row {} of vars", n + 1)
+            format!(
+                "Error while populating template with row {} of vars",
+                def.vars.row(n)
+            )
         })?;
         output.push(popped);
     }
 
     Ok(output)
 }
+
+/// The populated rows that share an `$outfile`
+#[derive(Debug, PartialEq)]
+pub struct Outfile {
+    /// The `$outfile` of these rows, [`None`] for rows with no `$outfile` or an empty one
+    ///
+    /// Rows naming the same file in different ways share an [`Outfile`], e.g. `./a.txt` and `dir/../a.txt` are both
+    /// `a.txt`
+    pub path: Option<PathBuf>,
+
+    /// Each populated row, in the same order as `vars`
+    pub rows: Vec<String>,
+}
+
+/// Like [`pop`] but groups each populated row by the special `$outfile` field in `vars`.
+///
+/// [`Outfile`]s are in the order their `$outfile` first appears in `vars`.
+pub fn pop_outfiles(input: &str, def: Definition) -> anyhow::Result<Vec<Outfile>> {
+    pop_outfiles_with_options(input, def, TemplateOptions::default())
+}
+
+/// Like [`pop_outfiles`] but compiles the template with the given [`TemplateOptions`]
+pub fn pop_outfiles_with_options(
+    input: &str,
+    def: Definition,
+    options: TemplateOptions,
+) -> anyhow::Result<Vec<Outfile>> {
+    let paths: Vec<Option<PathBuf>> = def
+        .vars
+        .iter()
+        .map(|var| {
+            var.get("$outfile")
+                .filter(|path| !path.is_empty())
+                .map(|path| normalise(Path::new(path)))
+        })
+        .collect();
+
+    let popped = pop_with_options(input, def, options)?;
+
+    let mut outfiles: Vec<Outfile> = Vec::new();
+    for (path, row) in paths.into_iter().zip(popped) {
+        match outfiles.iter_mut().find(|outfile| outfile.path == path) {
+            Some(outfile) => outfile.rows.push(row),
+            None => outfiles.push(Outfile {
+                path,
+                rows: vec![row],
+            }),
+        }
+    }
+
+    Ok(outfiles)
+}
+
+// `path` without any `.` and with `dir/..` removed, without touching the file system
+fn normalise(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(normal.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    normal
+}
@@ -1,38 +1,227 @@
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
-use clap::Parser;
-use popvars::Definition;
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use popvars::{Definition, Outfile, Template, TemplateOptions, TemplateSyntax};
+
+#[cfg(windows)]
+const LINE_ENDING: &str = "\r\n";
+
+#[cfg(unix)]
+const LINE_ENDING: &str = "\n";
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// The path to a csv file containing vars to be populated
-    #[arg(short, long)]
-    vars: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    definition: DefinitionArgs,
 
     /// The path to the template file to render
+    #[arg(short, long, required = true)]
+    template: Option<PathBuf>,
+
+    /// Write each row to the file named by its `$outfile`, relative to this directory. Rows without an `$outfile` are still printed
     #[arg(short, long)]
-    template: PathBuf,
+    out_dir: Option<PathBuf>,
+
+    /// Refuse to overwrite files that already exist in the out dir, nothing is written if any would be overwritten
+    #[arg(long, requires = "out_dir")]
+    no_clobber: bool,
+
+    #[command(flatten)]
+    template_args: TemplateArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the definitions for problems, such as duplicate `$id`s or values that don't refer to a record, and the template
+    /// for fields and lookups that don't exist, without rendering anything
+    Check(CheckArgs),
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    #[command(flatten)]
+    definition: DefinitionArgs,
+
+    /// The path to a template to check against the definitions as well, for unknown tables, fields and lookups
+    #[arg(short, long)]
+    template: Option<PathBuf>,
+
+    #[command(flatten)]
+    template_args: TemplateArgs,
+}
+
+#[derive(Args)]
+struct TemplateArgs {
+    /// Remove the first newline after each block tag, so block tags on their own line don't leave blank lines
+    #[arg(long)]
+    trim_blocks: bool,
+
+    /// Delimiters to use for expressions instead of `{{` and `}}`, e.g. `--expr-delimiters '<<' '>>'`
+    #[arg(long, num_args = 2, value_names = ["OPEN", "CLOSE"])]
+    expr_delimiters: Option<Vec<String>>,
+
+    /// Delimiters to use for blocks instead of `{@` and `@}`, e.g. `--block-delimiters '<%' '%>'`
+    #[arg(long, num_args = 2, value_names = ["OPEN", "CLOSE"])]
+    block_delimiters: Option<Vec<String>>,
+
+    /// Delimiters to use for comments instead of `{#` and `#}`, e.g. `--comment-delimiters '<#' '#>'`
+    #[arg(long, num_args = 2, value_names = ["OPEN", "CLOSE"])]
+    comment_delimiters: Option<Vec<String>>,
+}
+
+impl TemplateArgs {
+    /// Reads the template at `path`, along with the options to compile it with
+    fn read(&self, path: &Path) -> anyhow::Result<(String, TemplateOptions)> {
+        let template = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read template `{}`", path.display()))?;
+
+        let mut syntax = TemplateSyntax::default();
+        if let Some([open, close]) = self.expr_delimiters.as_deref() {
+            syntax.expr_open = open.clone();
+            syntax.expr_close = close.clone();
+        }
+        if let Some([open, close]) = self.block_delimiters.as_deref() {
+            syntax.block_open = open.clone();
+            syntax.block_close = close.clone();
+        }
+        if let Some([open, close]) = self.comment_delimiters.as_deref() {
+            syntax.comment_open = open.clone();
+            syntax.comment_close = close.clone();
+        }
+
+        let options = TemplateOptions {
+            trim_blocks: self.trim_blocks,
+            syntax,
+            name: Some(path.display().to_string()),
+            ..TemplateOptions::default()
+        };
+        Ok((template, options))
+    }
+}
+
+#[derive(Args)]
+struct DefinitionArgs {
+    /// The path to a csv file containing vars to be populated
+    #[arg(short, long, required_unless_present = "workbook")]
+    vars: Option<PathBuf>,
+
+    /// The path to a workbook (.xlsx, .xlsm, .xlsb, .xls or .ods) with a `$vars` sheet, every other sheet is a def named after the sheet
+    #[arg(short, long, conflicts_with_all = ["vars", "defs"])]
+    workbook: Option<PathBuf>,
 
     /// path to a .csv file containing a def (can be specified multiple times to pull in multiple defs) the def name will be the filename
     #[arg(short, long)]
     defs: Vec<PathBuf>,
 }
 
+impl DefinitionArgs {
+    fn load(&self) -> anyhow::Result<Definition> {
+        match (&self.workbook, &self.vars) {
+            (Some(workbook), _) => Definition::from_workbook(workbook),
+            (None, Some(vars)) => Definition::from_csv_files(vars, &self.defs),
+            (None, None) => unreachable!("clap requires either --vars or --workbook"),
+        }
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
-    let template = std::fs::read_to_string(&cli.template)?;
+    if let Some(Command::Check(args)) = &cli.command {
+        return check(args);
+    }
+
+    let template_path = cli
+        .template
+        .as_ref()
+        .expect("clap requires --template without a subcommand");
+    let (template, options) = cli.template_args.read(template_path)?;
+
+    let definition = cli.definition.load()?;
 
-    let definition = Definition::from_csv_files(&cli.vars, &cli.defs)?;
+    let Some(out_dir) = &cli.out_dir else {
+        let popped = popvars::pop_with_options(&template, definition, options)?;
+        println!("{}", popped.join(LINE_ENDING));
+        return Ok(());
+    };
 
-    let popped = popvars::pop(&template, definition)?;
+    let outfiles = popvars::pop_outfiles_with_options(&template, definition, options)?;
 
-    #[cfg(windows)]
-    println!("{}", popped.join("\r\n"));
+    let mut files = Vec::new();
+    for Outfile { path, rows } in outfiles {
+        match path {
+            Some(path) => files.push((out_path(out_dir, &path)?, rows)),
+            None => println!("{}", rows.join(LINE_ENDING)),
+        }
+    }
 
-    #[cfg(unix)]
-    println!("{}", popped.join("\n"));
+    if cli.no_clobber {
+        if let Some((existing, _)) = files.iter().find(|(path, _)| path.exists()) {
+            anyhow::bail!(
+                "Refusing to overwrite `{}` because --no-clobber was given",
+                existing.display()
+            );
+        }
+    }
 
+    for (path, rows) in files {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory `{}`", parent.display()))?;
+        }
+
+        let content = format!("{}{LINE_ENDING}", rows.join(LINE_ENDING));
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write `{}`", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Validates the definitions and checks the template against them if one is given, reporting every problem of both
+fn check(args: &CheckArgs) -> anyhow::Result<()> {
+    let definition = args.definition.load()?;
+
+    let mut problems = Vec::new();
+    if let Err(e) = definition.validate() {
+        problems.push(e.to_string());
+    }
+    if let Some(template_path) = &args.template {
+        let (template, options) = args.template_args.read(template_path)?;
+        let template = Template::compile_with_options(&template, options)?;
+        if let Err(e) = template.check(&definition) {
+            problems.push(e.to_string());
+        }
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(problems.join("\n\n"));
+    }
+    println!("No problems found");
     Ok(())
 }
+
+/// joins an `$outfile` onto the out dir, `$outfile`s must stay inside the out dir
+fn out_path(out_dir: &Path, outfile: &Path) -> anyhow::Result<PathBuf> {
+    // e.g. `.` or `dir/..`, which name the out dir itself once normalised
+    if outfile.as_os_str().is_empty() {
+        anyhow::bail!("`$outfile` must name a file inside the out dir, not the out dir itself");
+    }
+    if !outfile
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        anyhow::bail!(
+            "`$outfile` `{}` must be a relative path inside the out dir",
+            outfile.display()
+        );
+    }
+
+    Ok(out_dir.join(outfile))
+}
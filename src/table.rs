@@ -2,12 +2,23 @@ use std::collections::HashMap;
 use std::io;
 use std::slice::{Iter, IterMut};
 
+use anyhow::Context;
+use calamine::{DataType, Range};
 use csv::StringRecord;
 
 #[derive(Debug)]
 pub struct Table {
     pub name: String,
     pub records: Vec<Record>,
+
+    /// The fields in the header, in order. Known even if the table has no records
+    pub fields: Vec<Field>,
+
+    /// The [`ColumnType`] of each field that declared one in its header, every other field is text
+    pub types: HashMap<Field, ColumnType>,
+
+    /// The row each record was read from, the header is row 1. Empty rows are skipped, so these may have gaps
+    pub rows: Vec<usize>,
 }
 
 /// Fields are just Strings. They are appear in a table header.
@@ -27,32 +38,462 @@ pub type Value = String;
 /// [value]: Value
 pub type Record = HashMap<Field, Value>;
 
+/// Reads a [`Table`] from csv, the first row of which is the header.
+///
+/// Headers may declare a [`ColumnType`], e.g. `code:int`, see [`ColumnType::from_header`]
 pub fn from_csv<R: io::Read>(name: String, reader: R) -> anyhow::Result<Table> {
     let mut vars_csv = csv::Reader::from_reader(reader);
 
-    let fields: Vec<_> = vars_csv.headers()?.iter().map(str::to_string).collect();
+    let (fields, types): (Vec<Field>, Vec<Option<ColumnType>>) = vars_csv
+        .headers()?
+        .iter()
+        .map(ColumnType::from_header)
+        .unzip();
+
+    let mut errors = TypeErrors::new(&name);
 
-    let map_record = |result: Result<StringRecord, csv::Error>| {
+    let mut map_record = |n: usize, result: Result<StringRecord, csv::Error>| {
         result.map(|record| {
-            record
+            // row 1 is the header, blank lines are skipped so the line is used when it is known
+            let row = record
+                .position()
+                .map_or(n + 2, |position| position.line() as usize);
+            let record = record
                 .iter()
                 .enumerate()
-                .map(|(n, value)| (fields[n].to_owned(), value.to_owned()))
-                .collect::<HashMap<String, String>>()
+                .map(|(col, value)| {
+                    let value = errors.check(types[col], value.to_owned(), row, col + 1);
+                    (fields[col].to_owned(), value)
+                })
+                .collect::<HashMap<String, String>>();
+            (row, record)
         })
     };
 
-    let records = vars_csv
+    let (rows, records): (Vec<usize>, Vec<Record>) = vars_csv
         .records()
-        .map(map_record)
-        .collect::<Result<Vec<_>, _>>()?;
+        .enumerate()
+        .map(|(n, result)| map_record(n, result))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .unzip();
 
-    Ok(Table::new(name, records))
+    errors.finish()?;
+
+    Ok(Table::with_types(name, records, fields, types).with_rows(rows))
+}
+
+/// Reads a [`Table`] from a worksheet [`Range`], the first row of which is the header.
+///
+/// Columns with a blank header are skipped, as are rows where every cell is empty.
+/// Each cell is converted to a [`Value`] by [`cell_value`].
+///
+/// Headers may declare a [`ColumnType`], e.g. `code:int`, see [`ColumnType::from_header`]
+pub fn from_range(name: String, range: &Range<DataType>) -> anyhow::Result<Table> {
+    let mut rows = range.rows().enumerate();
+
+    // the range starts at the first cell that isn't empty, which isn't always A1
+    let (first_row, first_col) = range
+        .start()
+        .map_or((0, 0), |(row, col)| (row as usize, col as usize));
+
+    let Some((_, header)) = rows.next() else {
+        return Ok(Table::new(name, Vec::new()));
+    };
+
+    let fields = header
+        .iter()
+        .enumerate()
+        .map(|(col, cell)| {
+            cell_value(cell).map(|field| (col, field)).with_context(|| {
+                format!(
+                    "Invalid header in sheet `{name}` row {} column {}",
+                    first_row + 1,
+                    first_col + col + 1
+                )
+            })
+        })
+        .filter(|result| {
+            result
+                .as_ref()
+                .map_or(true, |(_, field)| !field.trim().is_empty())
+        })
+        .collect::<anyhow::Result<Vec<(usize, Field)>>>()?;
+
+    let fields: Vec<(usize, Field, Option<ColumnType>)> = fields
+        .into_iter()
+        .map(|(col, header)| {
+            let (field, column_type) = ColumnType::from_header(&header);
+            (col, field, column_type)
+        })
+        .collect();
+
+    let mut errors = TypeErrors::new(&name);
+
+    let (rows, records): (Vec<usize>, Vec<Record>) = rows
+        .filter(|(_, row)| row.iter().any(|cell| !cell.is_empty()))
+        .map(|(n, row)| {
+            let n = first_row + n;
+            let record = fields
+                .iter()
+                .map(|(col, field, column_type)| {
+                    let value = match row.get(*col) {
+                        Some(cell) => cell_value(cell).with_context(|| {
+                            format!(
+                                "Invalid cell in sheet `{name}` row {} column {}",
+                                n + 1,
+                                first_col + col + 1
+                            )
+                        })?,
+                        None => Value::new(),
+                    };
+                    let value = errors.check(*column_type, value, n + 1, first_col + col + 1);
+                    Ok::<_, anyhow::Error>((field.to_owned(), value))
+                })
+                .collect::<anyhow::Result<Record>>()?;
+            Ok((n + 1, record))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+
+    errors.finish()?;
+
+    let (fields, types) = fields
+        .into_iter()
+        .map(|(_, field, column_type)| (field, column_type))
+        .unzip();
+
+    Ok(Table::with_types(name, records, fields, types).with_rows(rows))
+}
+
+/// The type of the values in a column, declared after a colon in its header, e.g. `code:int`
+///
+/// Values are checked when the table is loaded and always written the same way, so that comparisons,
+/// sorting and filters don't trip over a value like `"42 "` halfway through populating a template.
+/// Empty values are allowed in every column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    /// Any value, the same as not declaring a type
+    Text,
+    /// A signed integer, e.g. `-45`
+    Int,
+    /// A number, e.g. `0.5` or `1e300`, kept as it was written
+    Float,
+    /// `true` or `false`, `yes`, `no`, `1` and `0` are also accepted
+    Bool,
+    /// An ISO 8601 date, e.g. `1950-05-01`, optionally with a time of day, e.g. `1950-05-01T12:30:00`
+    Date,
+}
+
+impl ColumnType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnType::Text => "text",
+            ColumnType::Int => "int",
+            ColumnType::Float => "float",
+            ColumnType::Bool => "bool",
+            ColumnType::Date => "date",
+        }
+    }
+
+    /// Splits a header such as `code:int` into its field and type.
+    ///
+    /// A header that doesn't end with the name of a type is all field, e.g. `note:todo`
+    pub fn from_header(header: &str) -> (Field, Option<ColumnType>) {
+        let column_type = header.rsplit_once(':').and_then(|(field, name)| {
+            [
+                ColumnType::Text,
+                ColumnType::Int,
+                ColumnType::Float,
+                ColumnType::Bool,
+                ColumnType::Date,
+            ]
+            .into_iter()
+            .find(|column_type| column_type.name() == name.trim())
+            .map(|column_type| (field.trim_end().to_string(), column_type))
+        });
+
+        match column_type {
+            Some((field, column_type)) => (field, Some(column_type)),
+            None => (header.to_string(), None),
+        }
+    }
+
+    /// Checks that `value` is of this type and returns it written the standard way for this type
+    pub fn normalize(&self, value: &str) -> anyhow::Result<Value> {
+        let trimmed = value.trim();
+        if trimmed.is_empty() {
+            return Ok(Value::new());
+        }
+
+        let normalized = match self {
+            ColumnType::Text => Some(value.to_string()),
+            ColumnType::Int => trimmed.parse::<i64>().ok().map(|int| int.to_string()),
+            ColumnType::Float => trimmed
+                .parse::<f64>()
+                .ok()
+                .filter(|float| float.is_finite())
+                .map(|_| trimmed.to_string()),
+            ColumnType::Bool => match trimmed.to_lowercase().as_str() {
+                "true" | "yes" | "1" => Some(String::from("true")),
+                "false" | "no" | "0" => Some(String::from("false")),
+                _ => None,
+            },
+            ColumnType::Date => is_iso_date(trimmed).then(|| trimmed.to_string()),
+        };
+
+        normalized.ok_or_else(|| anyhow::anyhow!("expected `{value}` to be {}", self.description()))
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            ColumnType::Text => "text",
+            ColumnType::Int => "an integer",
+            ColumnType::Float => "a number",
+            ColumnType::Bool => "true or false",
+            ColumnType::Date => "a date like 1950-05-01",
+        }
+    }
+}
+
+/// `1950-05-01` or `1950-05-01T12:30:00`, the time may leave out the seconds
+fn is_iso_date(value: &str) -> bool {
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+
+    let number = |part: &str, len: usize| {
+        (part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| part.parse::<i64>().ok())
+            .flatten()
+    };
+
+    let mut parts = date.split('-');
+    let (Some(year), Some(month), Some(day), None) = (
+        parts.next().and_then(|part| number(part, 4)),
+        parts.next().and_then(|part| number(part, 2)),
+        parts.next().and_then(|part| number(part, 2)),
+        parts.next(),
+    ) else {
+        return false;
+    };
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return false;
+    }
+
+    let Some(time) = time else {
+        return true;
+    };
+    let parts: Vec<_> = time.split(':').map(|part| number(part, 2)).collect();
+    match parts[..] {
+        [Some(h), Some(m)] => h < 24 && m < 60,
+        [Some(h), Some(m), Some(s)] => h < 24 && m < 60 && s < 60,
+        _ => false,
+    }
+}
+
+/// Collects every value that isn't of its column's [`ColumnType`] while a table is loaded,
+/// so that they are all reported together
+struct TypeErrors<'n> {
+    table_name: &'n str,
+    errors: Vec<String>,
+}
+
+impl<'n> TypeErrors<'n> {
+    fn new(table_name: &'n str) -> Self {
+        TypeErrors {
+            table_name,
+            errors: Vec::new(),
+        }
+    }
+
+    /// returns the normalized value, or the value as it was if it is invalid
+    fn check(
+        &mut self,
+        column_type: Option<ColumnType>,
+        value: Value,
+        row: usize,
+        col: usize,
+    ) -> Value {
+        let Some(column_type) = column_type else {
+            return value;
+        };
+
+        match column_type.normalize(&value) {
+            Ok(normalized) => normalized,
+            Err(e) => {
+                self.errors.push(format!("row {row} column {col}: {e}"));
+                value
+            }
+        }
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+
+        anyhow::bail!(
+            "Invalid values in `{}`:\n{}",
+            self.table_name,
+            self.errors.join("\n")
+        )
+    }
+}
+
+/// Converts a spreadsheet cell into a [`Value`].
+///
+/// The conversion is the same whatever format the workbook was saved in,
+/// so a template renders the same from `.ods`, `.xlsx`, `.xlsm`, `.xlsb` or `.xls`:
+///
+/// * empty cells are the empty string
+/// * text is used as is
+/// * integers and whole floats are written without a decimal point, e.g. `45` not `45.0`,
+///   other floats use the shortest representation that round trips, e.g. `0.1`, and never use an exponent
+/// * booleans are `true` or `false`
+/// * dates are ISO 8601: `1950-05-01`, or `1950-05-01T12:30:00` if the cell has a time of day.
+///   Serial dates are counted from 1899-12-30, the 1900 date system
+/// * durations are `HH:MM:SS`, hours may exceed 24
+/// * formulas use the value cached when the workbook was last saved
+/// * error cells such as `#DIV/0!` are an error, rather than silently becoming text
+pub fn cell_value(cell: &DataType) -> anyhow::Result<Value> {
+    let value = match cell {
+        DataType::Empty => Value::new(),
+        DataType::String(text) => text.clone(),
+        DataType::Int(int) => int.to_string(),
+        DataType::Float(float) => float.to_string(),
+        DataType::Bool(bool) => bool.to_string(),
+        DataType::DateTime(serial) => serial_datetime(*serial),
+        DataType::DateTimeIso(iso) => iso.clone(),
+        DataType::Duration(days) => duration(*days),
+        DataType::DurationIso(iso) => iso.clone(),
+        DataType::Error(e) => anyhow::bail!("cell contains the error `{e}`"),
+    };
+
+    Ok(value)
+}
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Days between 1899-12-30, day 0 of the 1900 date system, and the unix epoch
+const SERIAL_UNIX_EPOCH: i64 = 25_569;
+
+/// splits a serial date into whole days and seconds since midnight, rounding to the nearest second
+fn serial_parts(serial: f64) -> (i64, i64) {
+    let seconds = (serial * SECONDS_PER_DAY as f64).round() as i64;
+    (
+        seconds.div_euclid(SECONDS_PER_DAY),
+        seconds.rem_euclid(SECONDS_PER_DAY),
+    )
+}
+
+fn serial_datetime(serial: f64) -> Value {
+    let (days, seconds) = serial_parts(serial);
+    let (year, month, day) = civil_from_days(days - SERIAL_UNIX_EPOCH);
+
+    if seconds == 0 {
+        format!("{year:04}-{month:02}-{day:02}")
+    } else {
+        let (h, m, s) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+        format!("{year:04}-{month:02}-{day:02}T{h:02}:{m:02}:{s:02}")
+    }
+}
+
+fn duration(days: f64) -> Value {
+    let seconds = (days * SECONDS_PER_DAY as f64).round() as i64;
+    let sign = if seconds < 0 { "-" } else { "" };
+    let seconds = seconds.abs();
+    let (h, m, s) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+    format!("{sign}{h:02}:{m:02}:{s:02}")
+}
+
+/// Converts days since the unix epoch into a (year, month, day) in the proleptic Gregorian calendar
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
 }
 
 impl Table {
+    /// A [`Table`] whose fields are those of its first record, sorted by name
     pub fn new(name: String, records: Vec<Record>) -> Self {
-        Table { name, records }
+        let mut fields: Vec<Field> = records
+            .first()
+            .map(|record| record.keys().cloned().collect())
+            .unwrap_or_default();
+        fields.sort();
+        let rows = (2..records.len() + 2).collect();
+
+        Table {
+            name,
+            records,
+            fields,
+            types: HashMap::new(),
+            rows,
+        }
+    }
+
+    /// A [`Table`] whose fields may have declared a [`ColumnType`], `fields` and `types` are in the same order
+    pub fn with_types(
+        name: String,
+        records: Vec<Record>,
+        fields: Vec<Field>,
+        types: Vec<Option<ColumnType>>,
+    ) -> Self {
+        let types = fields
+            .iter()
+            .zip(types)
+            .filter_map(|(field, column_type)| Some((field.clone(), column_type?)))
+            .collect();
+        let rows = (2..records.len() + 2).collect();
+
+        Table {
+            name,
+            records,
+            fields,
+            types,
+            rows,
+        }
+    }
+
+    /// Sets the row each record was read from, see [`Table::rows`]
+    pub fn with_rows(mut self, rows: Vec<usize>) -> Self {
+        self.rows = rows;
+        self
+    }
+
+    /// The row the `n`th record was read from, the header is row 1
+    pub fn row(&self, n: usize) -> usize {
+        self.rows.get(n).copied().unwrap_or(n + 2)
+    }
+
+    pub fn has_field(&self, field: &str) -> bool {
+        self.fields.iter().any(|f| f == field)
+    }
+
+    /// The [`ColumnType`] declared in the header of `field`, if any
+    pub fn column_type(&self, field: &str) -> Option<ColumnType> {
+        self.types.get(field).copied()
     }
 
     pub fn index<'c>(&'c self, index: &'c str) -> anyhow::Result<Option<&'c Record>> {
@@ -131,4 +572,114 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_cell_value() -> anyhow::Result<()> {
+        assert_eq!(cell_value(&DataType::Empty)?, "");
+        assert_eq!(cell_value(&DataType::String("Allies".into()))?, "Allies");
+        assert_eq!(cell_value(&DataType::Int(-7))?, "-7");
+        assert_eq!(cell_value(&DataType::Float(45.0))?, "45");
+        assert_eq!(cell_value(&DataType::Float(0.1))?, "0.1");
+        assert_eq!(
+            cell_value(&DataType::Float(1e21))?,
+            "1000000000000000000000"
+        );
+        assert_eq!(cell_value(&DataType::Bool(true))?, "true");
+        assert_eq!(cell_value(&DataType::DateTime(18384.0))?, "1950-05-01");
+        assert_eq!(
+            cell_value(&DataType::DateTime(18384.5))?,
+            "1950-05-01T12:00:00"
+        );
+        assert_eq!(cell_value(&DataType::DateTime(25569.0))?, "1970-01-01");
+        assert_eq!(cell_value(&DataType::Duration(1.25))?, "30:00:00");
+        assert_eq!(
+            cell_value(&DataType::DateTimeIso("1950-05-01".into()))?,
+            "1950-05-01"
+        );
+
+        assert!(cell_value(&DataType::Error(calamine::CellErrorType::Div0)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_column_type_from_header() {
+        assert_eq!(
+            ColumnType::from_header("code:int"),
+            (String::from("code"), Some(ColumnType::Int))
+        );
+        assert_eq!(
+            ColumnType::from_header("founded : date"),
+            (String::from("founded"), Some(ColumnType::Date))
+        );
+        assert_eq!(
+            ColumnType::from_header("code"),
+            (String::from("code"), None)
+        );
+        assert_eq!(
+            ColumnType::from_header("note:todo"),
+            (String::from("note:todo"), None)
+        );
+    }
+
+    #[test]
+    fn test_column_type_normalize() -> anyhow::Result<()> {
+        assert_eq!(ColumnType::Int.normalize("42 ")?, "42");
+        assert_eq!(ColumnType::Int.normalize("")?, "");
+        assert!(ColumnType::Int.normalize("4.2").is_err());
+        assert_eq!(ColumnType::Float.normalize("1.50 ")?, "1.50");
+        assert_eq!(ColumnType::Float.normalize("1e300")?, "1e300");
+        assert!(ColumnType::Float.normalize("inf").is_err());
+        assert_eq!(ColumnType::Bool.normalize("Yes")?, "true");
+        assert_eq!(ColumnType::Bool.normalize("0")?, "false");
+        assert!(ColumnType::Bool.normalize("maybe").is_err());
+        assert_eq!(ColumnType::Date.normalize("1950-05-01")?, "1950-05-01");
+        assert_eq!(
+            ColumnType::Date.normalize("1950-05-01T12:30")?,
+            "1950-05-01T12:30"
+        );
+        assert!(ColumnType::Date.normalize("1950-02-30").is_err());
+        assert!(ColumnType::Date.normalize("01/05/1950").is_err());
+        assert_eq!(ColumnType::Text.normalize(" as is ")?, " as is ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_csv_types() -> anyhow::Result<()> {
+        let csv = "$id,code:int,ally:bool\nGermany, 45 ,yes\nFrance,40,no\n";
+        let table = from_csv("country".into(), csv.as_bytes())?;
+
+        assert_eq!(table.records[0].get("code"), Some(&String::from("45")));
+        assert_eq!(table.records[0].get("ally"), Some(&String::from("true")));
+        assert_eq!(table.column_type("code"), Some(ColumnType::Int));
+        assert_eq!(table.column_type("$id"), None);
+
+        // every invalid value is reported at once
+        let csv = "$id,code:int,ally:bool\nGermany,forty five,yes\nFrance,40,perhaps\n";
+        let Err(err) = from_csv("country".into(), csv.as_bytes()) else {
+            panic!("expected invalid values to fail to load");
+        };
+        assert_eq!(
+            err.to_string(),
+            "Invalid values in `country`:\n\
+             row 2 column 2: expected `forty five` to be an integer\n\
+             row 3 column 3: expected `perhaps` to be true or false"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_csv_rows() -> anyhow::Result<()> {
+        // blank lines are skipped, but still counted in the row numbers
+        let csv = "$id,code:int\nGermany,45\n\nFrance,forty\n";
+        let Err(err) = from_csv("country".into(), csv.as_bytes()) else {
+            panic!("expected an invalid value to fail to load");
+        };
+        assert!(err.to_string().contains("row 4 column 2"), "{err}");
+
+        let table = from_csv("country".into(), "$id\nGermany\n\nFrance\n".as_bytes())?;
+        assert_eq!(table.rows, vec![2, 4]);
+        assert_eq!(table.row(1), 4);
+        Ok(())
+    }
 }
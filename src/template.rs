@@ -5,96 +5,496 @@
 //! * parse -> Vec<Node>
 //! * compile -> Template (with ContextIndexes set for each Expr)
 
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
 
-use anyhow::anyhow;
+use anyhow::Context as _;
 use parsely::result_ext::*;
 
 use crate::{
-    expr::{template, Block, BlockExpr, Comparison, Node},
-    table::Value,
-    Definition, Expr, Record,
+    expr::{
+        self, AssignedValue, Block, BlockExpr, CallTag, Condition, ElseBranch, Expand, Filters,
+        LoopOrder, Node, PopValue, Span, TemplateSyntax,
+    },
+    Definition, Expr, ExprKind, Record,
 };
 
 /// [`Template`]s consists of an ordered list of [`Node`]s to be rendered in order and a
 /// [`ContextIndex`] of blocks to access the required context for populating
-#[derive(PartialEq, Debug)]
+#[derive(Debug)]
 pub struct Template {
     nodes: Vec<CompiledNode>,
+    filters: Filters,
+
+    /// The template itself, then any templates it included or imported, see [`Location`]
+    sources: Vec<Source>,
 }
 
-#[derive(PartialEq, Debug)]
+/// Templates are equal when they compiled to the same nodes from the same sources, their filters can't be compared
+impl PartialEq for Template {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes == other.nodes && self.sources == other.sources
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub enum CompiledNode {
     Text(String),
-    Expr(Expr),
+    Expr(Expr, Location),
     Block(CompiledBlock),
+    Call(CompiledCall),
+}
+
+/// The text of a template that was compiled into a [`Template`]
+#[derive(PartialEq, Debug, Clone)]
+pub struct Source {
+    /// The path of an included or imported template, or the [name](TemplateOptions::name) of the template itself
+    pub name: Option<String>,
+    pub text: String,
+}
+
+impl Source {
+    /// e.g. `line 3 column 5`, or `` `header.txt` line 3 column 5 `` if the source has a name
+    pub fn describe(&self, span: Span) -> String {
+        let (line, column) = span.line_column(&self.text);
+
+        match &self.name {
+            Some(name) => format!("`{name}` line {line} column {column}"),
+            None => format!("line {line} column {column}"),
+        }
+    }
+
+    /// Where `span` is, followed by the line it starts on with the span underlined:
+    ///
+    /// ```text
+    /// At `greeting.txt` line 3 column 7:
+    ///   |
+    /// 3 | Hello {{nmae}}!
+    ///   |       ^^^^^^^^
+    /// ```
+    ///
+    /// A span over several lines is underlined to the end of its first line
+    pub fn snippet(&self, span: Span) -> String {
+        let (line, column) = span.line_column(&self.text);
+        let text = self.text.lines().nth(line - 1).unwrap_or_default();
+        let underlined = self
+            .text
+            .get(span.start..span.end)
+            .and_then(|spanned| spanned.lines().next())
+            .map_or(0, |spanned| spanned.chars().count())
+            .max(1);
+
+        let gutter = " ".repeat(line.to_string().len());
+        format!(
+            "At {}:\n{gutter} |\n{line} | {text}\n{gutter} | {}{}",
+            self.describe(span),
+            " ".repeat(column - 1),
+            "^".repeat(underlined),
+        )
+    }
+}
+
+/// Where a [`CompiledNode`] was parsed from, a [`Span`] of one of the [`Template`]'s [`Source`]s
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Location {
+    /// The index of the [`Source`] in the [`Template`]
+    pub source: usize,
+    pub span: Span,
 }
 
 /// A [`CompiledBlock`] is a [`Block`] of [`CompiledNode`]s
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct CompiledBlock {
     pub expr: BlockExpr,
     pub nodes: Vec<CompiledNode>,
+    // the opening tag
+    pub location: Location,
     // not all Blocks provide new context
     pub block_ctx_idx: Option<(String, ContextIndex)>,
+    // only if Blocks have else branches, the first branch is expr and nodes
+    pub else_branches: Vec<CompiledBranch>,
+}
+
+/// A [`CompiledCall`] is a [`BlockExpr::Call`] along with the body of the macro it calls
+#[derive(PartialEq, Debug, Clone)]
+pub struct CompiledCall {
+    pub call_tag: CallTag,
+    // the parameters of the called macro, which its nodes are populated with
+    pub params: Vec<String>,
+    pub nodes: Vec<CompiledNode>,
+    pub location: Location,
+}
+
+/// A [`CompiledBranch`] is an [`ElseBranch`] of [`CompiledNode`]s
+#[derive(PartialEq, Debug, Clone)]
+pub struct CompiledBranch {
+    pub condition: Option<Condition>,
+    pub nodes: Vec<CompiledNode>,
+}
+
+/// Options that change how a [`Template`] is compiled, see [`Template::compile_with_options`]
+#[derive(Clone, Default)]
+pub struct TemplateOptions {
+    /// Custom filters that may be used as well as the built-in filters
+    pub filters: Filters,
+
+    /// Removes the first newline after each block tag, so a block tag on a line of its own doesn't leave a blank line.
+    ///
+    /// Included and imported templates are trimmed too.
+    pub trim_blocks: bool,
+
+    /// The delimiters of expressions and blocks, used by included and imported templates too
+    pub syntax: TemplateSyntax,
+
+    /// The name of the template in errors, e.g. the path it was read from
+    pub name: Option<String>,
 }
 
 impl Template {
     pub fn compile(input: &str) -> anyhow::Result<Self> {
-        let (nodes, _) = template(input).own_err()?;
+        Template::compile_with_options(input, TemplateOptions::default())
+    }
 
-        let compiled_nodes: Vec<CompiledNode> =
-            nodes.into_iter().map(Template::compile_node).collect();
+    /// Compiles a template that may use the custom filters in `filters` as well as the built-in filters.
+    ///
+    /// Fails if the template uses a filter that is neither built-in nor in `filters`
+    pub fn compile_with_filters(input: &str, filters: Filters) -> anyhow::Result<Self> {
+        Template::compile_with_options(
+            input,
+            TemplateOptions {
+                filters,
+                ..TemplateOptions::default()
+            },
+        )
+    }
+
+    /// Compiles a template with the given [`TemplateOptions`]
+    pub fn compile_with_options(input: &str, options: TemplateOptions) -> anyhow::Result<Self> {
+        let TemplateOptions {
+            filters,
+            trim_blocks,
+            syntax,
+            name,
+        } = options;
+
+        syntax.validate()?;
+
+        let mut compiler = Compiler {
+            filters: &filters,
+            trim_blocks,
+            syntax: &syntax,
+            includes: Vec::new(),
+            macros: HashMap::new(),
+            sources: Vec::new(),
+            source: 0,
+        };
+        let nodes = compiler.compile(Source {
+            name,
+            text: input.to_string(),
+        })?;
+        let sources = compiler.sources;
 
         Ok(Template {
-            nodes: compiled_nodes,
+            nodes,
+            filters,
+            sources,
         })
     }
 
-    pub fn compile_node(node: Node) -> CompiledNode {
-        match node {
+    /// Checks the template against `def` without populating it, reporting every problem found at once:
+    ///
+    /// * tables that don't exist, e.g. `{@ for c in contry @}`
+    /// * fields that don't exist in the table they are expanded from, e.g. `{{country.cod}}`
+    /// * lookups through fields that don't exist, e.g. `{{country@Enemy.code}}` without an `Enemy` field in vars
+    /// * loop contexts used outside of their loop
+    ///
+    /// Each problem names the line and column of the expression or block tag it was found in.
+    /// The body of a macro is checked wherever it is called.
+    pub fn check(&self, def: &Definition) -> anyhow::Result<()> {
+        let problems = expr::check(&self.nodes, def);
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let problems: Vec<String> = problems
+            .iter()
+            .map(|(location, problem)| {
+                let source = &self.sources[location.source];
+                format!("- {}: {problem}", source.describe(location.span))
+            })
+            .collect();
+        anyhow::bail!(
+            "Found {} problems in the template:\n{}",
+            problems.len(),
+            problems.join("\n")
+        )
+    }
+
+    /// Where `location` is in its source and the line it is on, see [`Source::snippet`]
+    fn snippet(&self, location: &Location) -> String {
+        self.sources[location.source].snippet(location.span)
+    }
+
+    #[allow(unused)]
+    pub fn pop(&self, record: &Record, def: &Definition) -> anyhow::Result<String> {
+        let mut output = String::new();
+
+        let ctx = InheritedContext::default();
+
+        pop_nodes(&self.nodes, &mut output, record, def, &ctx, self)?;
+
+        Ok(output)
+    }
+}
+
+/// Compiles parsed [`Node`]s into [`CompiledNode`]s
+struct Compiler<'f> {
+    filters: &'f Filters,
+
+    /// see [`TemplateOptions::trim_blocks`]
+    trim_blocks: bool,
+
+    syntax: &'f TemplateSyntax,
+
+    /// the paths of the templates currently being included, see [`Compiler::compile_include`]
+    includes: Vec<PathBuf>,
+
+    /// the macros that can be called, by name, see [`Compiler::scoped`]
+    macros: HashMap<String, CompiledMacro>,
+
+    /// every template compiled so far, see [`Template::sources`]
+    sources: Vec<Source>,
+
+    /// the index of the template being compiled in `sources`
+    source: usize,
+}
+
+/// The parameters and compiled body of a [`BlockExpr::Macro`]
+#[derive(Clone)]
+struct CompiledMacro {
+    params: Vec<String>,
+    nodes: Vec<CompiledNode>,
+}
+
+impl Compiler<'_> {
+    fn compile(&mut self, source: Source) -> anyhow::Result<Vec<CompiledNode>> {
+        let (mut nodes, _) = match self.syntax.template(&source.text).own_err() {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let span = self.syntax.failed_at(&source.text);
+                return Err(anyhow::Error::from(e).context(source.snippet(span)));
+            }
+        };
+
+        if self.trim_blocks {
+            trim_blocks(&mut nodes);
+        }
+
+        let outer = self.source;
+        self.source = self.sources.len();
+        self.sources.push(source);
+        let compiled = self.compile_nodes(nodes);
+        self.source = outer;
+
+        compiled
+    }
+
+    fn location(&self, span: Span) -> Location {
+        Location {
+            source: self.source,
+            span,
+        }
+    }
+
+    fn compile_nodes(&mut self, nodes: Vec<Node>) -> anyhow::Result<Vec<CompiledNode>> {
+        nodes
+            .into_iter()
+            .map(|node| self.compile_node(node))
+            .collect()
+    }
+
+    /// Compiles within a block, macros defined inside it can't be called after it ends
+    fn scoped<T>(
+        &mut self,
+        compile: impl FnOnce(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let outer = self.macros.clone();
+        let compiled = compile(self);
+        self.macros = outer;
+        compiled
+    }
+
+    fn compile_node(&mut self, node: Node) -> anyhow::Result<CompiledNode> {
+        let compiled_node = match node {
             Node::Text(string) => CompiledNode::Text(string),
-            Node::Expr(expr) => match expr {
-                Expr::Expand(_) => CompiledNode::Expr(expr),
-            },
-            Node::Block(Block { expr, nodes }) => {
+            Node::Expr(expr) => {
+                self.check_expr(&expr.kind)?;
+                let location = self.location(expr.span);
+                CompiledNode::Expr(expr, location)
+            }
+            Node::Block(Block {
+                expr: BlockExpr::Call(call_tag),
+                span,
+                ..
+            }) => CompiledNode::Call(self.compile_call(call_tag, span)?),
+            Node::Block(Block {
+                expr,
+                nodes,
+                span,
+                else_branches,
+            }) => {
                 let (compiled_block_nodes, block_ctx_idx) = match expr {
                     BlockExpr::ForTag(ref for_tag) => (
-                        nodes
-                            .into_iter()
-                            .map(Template::compile_node)
-                            .collect::<Vec<_>>(),
-                        Some((for_tag.new_context_name.clone(), for_tag.ctx_idx())),
+                        self.scoped(|compiler| compiler.compile_nodes(nodes))?,
+                        Some((for_tag.new_context_name.clone(), for_tag.ctx_idx()?)),
                     ),
-                    BlockExpr::If(_) => (
-                        nodes
-                            .into_iter()
-                            .map(Template::compile_node)
-                            .collect::<Vec<_>>(),
+                    BlockExpr::If(_) => {
+                        (self.scoped(|compiler| compiler.compile_nodes(nodes))?, None)
+                    }
+                    BlockExpr::Pop(ref pop_tag) => (
+                        self.scoped(|compiler| compiler.compile_include(&pop_tag.path))?,
                         None,
                     ),
+                    BlockExpr::Set(ref assignment) | BlockExpr::With(ref assignment) => {
+                        if let AssignedValue::Expr(ref expr) = assignment.value {
+                            self.check_expr(expr)?;
+                        }
+                        (self.scoped(|compiler| compiler.compile_nodes(nodes))?, None)
+                    }
+                    // a macro's body is populated where it is called, not where it is defined
+                    BlockExpr::Macro(ref macro_tag) => {
+                        let compiled_macro = CompiledMacro {
+                            params: macro_tag.params.clone(),
+                            nodes: self.scoped(|compiler| compiler.compile_nodes(nodes))?,
+                        };
+                        self.macros.insert(macro_tag.name.clone(), compiled_macro);
+                        (Vec::new(), None)
+                    }
+                    BlockExpr::Call(_) => {
+                        unreachable!("calls are compiled by Compiler::compile_call")
+                    }
+                    // only the macros defined by an imported template are used, and can be called after the import
+                    BlockExpr::Import(ref path) => {
+                        self.compile_include(path)?;
+                        (Vec::new(), None)
+                    }
                 };
 
+                let else_branches = else_branches
+                    .into_iter()
+                    .map(|ElseBranch { condition, nodes }| {
+                        Ok::<_, anyhow::Error>(CompiledBranch {
+                            condition,
+                            nodes: self.scoped(|compiler| compiler.compile_nodes(nodes))?,
+                        })
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+
                 CompiledNode::Block(CompiledBlock {
                     expr,
                     nodes: compiled_block_nodes,
+                    location: self.location(span),
                     block_ctx_idx,
+                    else_branches,
                 })
             }
+        };
+
+        Ok(compiled_node)
+    }
+
+    /// Compiles a [`BlockExpr::Call`] along with the body of the macro it calls
+    fn compile_call(&mut self, call_tag: CallTag, span: Span) -> anyhow::Result<CompiledCall> {
+        let Some(called) = self.macros.get(&call_tag.name) else {
+            anyhow::bail!(
+                "Unknown macro `{}`, macros must be defined or imported before they are called",
+                call_tag.name
+            );
+        };
+        if called.params.len() != call_tag.args.len() {
+            anyhow::bail!(
+                "Macro `{}` takes {} arguments but was called with {}",
+                call_tag.name,
+                called.params.len(),
+                call_tag.args.len()
+            );
         }
+        let params = called.params.clone();
+        let nodes = called.nodes.clone();
+
+        for arg in &call_tag.args {
+            if let AssignedValue::Expr(expr) = arg {
+                self.check_expr(expr)?;
+            }
+        }
+
+        Ok(CompiledCall {
+            call_tag,
+            params,
+            nodes,
+            location: self.location(span),
+        })
     }
 
-    #[allow(unused)]
-    pub fn pop(&self, record: &Record, def: &Definition) -> anyhow::Result<String> {
-        let mut output = String::new();
+    /// Fails if `expr` uses an unknown filter or is an invalid [`Aggregate`](crate::expr::Aggregate)
+    fn check_expr(&self, expr: &ExprKind) -> anyhow::Result<()> {
+        if let Some(unknown) = expr
+            .filters()
+            .iter()
+            .find(|filter| !self.filters.contains(&filter.name))
+        {
+            anyhow::bail!("Unknown filter `{}` used in `{expr:?}`", unknown.name);
+        }
+        if let ExprKind::Aggregate(aggregate) = expr {
+            aggregate.validate()?;
+        }
 
-        let mut ctx: InheritedContext = HashMap::new();
+        Ok(())
+    }
 
-        for node in self.nodes.iter() {
-            node.pop(&mut output, record, def, &ctx)?;
+    /// Reads and compiles the template included by a [`BlockExpr::Pop`]
+    ///
+    /// `path` is relative to the directory of the template doing the including, or to the current directory for the
+    /// top level template. Fails if the template is already being included, since that include would never end
+    fn compile_include(&mut self, path: &str) -> anyhow::Result<Vec<CompiledNode>> {
+        let relative_to = self
+            .includes
+            .last()
+            .and_then(|parent| parent.parent())
+            .unwrap_or_else(|| Path::new(""));
+        let canonical_path = relative_to
+            .join(path)
+            .canonicalize()
+            .with_context(|| format!("Failed to find included template `{path}`"))?;
+
+        if let Some(start) = self.includes.iter().position(|p| p == &canonical_path) {
+            let cycle = self.includes[start..]
+                .iter()
+                .chain(std::iter::once(&canonical_path))
+                .map(|p| format!("`{}`", p.display()))
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            anyhow::bail!("Include cycle detected: {cycle}");
         }
 
-        Ok(output)
+        let text = std::fs::read_to_string(&canonical_path)
+            .with_context(|| format!("Failed to read included template `{path}`"))?;
+        let source = Source {
+            name: Some(path.to_string()),
+            text,
+        };
+
+        self.includes.push(canonical_path);
+        let included = self
+            .compile(source)
+            .with_context(|| format!("Failed to compile included template `{path}`"));
+        self.includes.pop();
+
+        included
     }
 }
 
@@ -106,13 +506,19 @@ impl CompiledNode {
         def: &'d Definition,
         // blocks and like parent blocks
         ctx: &'b InheritedContext,
+        // the template this node was compiled into, for its filters and sources
+        template: &Template,
     ) -> anyhow::Result<()>
     where
         'b: 'd,
     {
         match self {
-            CompiledNode::Expr(Expr::Expand(expand)) => {
-                output.push_str(&expand.run(record, &def.defs, ctx)?);
+            CompiledNode::Expr(expr, location) => {
+                let value = expr
+                    .kind
+                    .run(record, def, ctx, &template.filters)
+                    .with_context(|| template.snippet(location))?;
+                output.push_str(&value);
                 Ok(())
             }
             CompiledNode::Block(block) => match &block.expr {
@@ -121,31 +527,156 @@ impl CompiledNode {
                         .block_ctx_idx
                         .as_ref()
                         .expect("ForTag always has a new Context");
-                    let contexts = def
-                        .index(ctx_idx, record, ctx)
-                        .ok_or_else(|| anyhow!("Failed to index context `{ctx_name}` for block"))?;
+                    // each iteration's context, along with the records in its group for group by loops
+                    let contexts: Vec<(Record, Option<Vec<Record>>)> = match ctx_idx {
+                        ContextIndex::GroupBy { index, key } => def
+                            .groups(index, key, record, ctx)
+                            .with_context(|| format!("Failed to group context `{ctx_name}`"))
+                            .with_context(|| template.snippet(&block.location))?
+                            .into_iter()
+                            .map(|(key, records)| {
+                                let records = records.into_iter().cloned().collect();
+                                (Record::from([("$id".to_string(), key)]), Some(records))
+                            })
+                            .collect(),
+                        _ => def
+                            .index(ctx_idx, record, ctx)
+                            .and_then(|records| {
+                                records
+                                    .map(|loop_ctx| Ok((loop_ctx?.clone(), None)))
+                                    .collect::<anyhow::Result<Vec<_>>>()
+                            })
+                            .with_context(|| {
+                                format!("Failed to index context `{ctx_name}` for block")
+                            })
+                            .with_context(|| template.snippet(&block.location))?,
+                    };
+
+                    // the contexts of value list and group by loops stand for their value, the others for a record
+                    let is_value = matches!(
+                        ctx_idx,
+                        ContextIndex::ValueList(_) | ContextIndex::GroupBy { .. }
+                    );
 
                     // PERF: avoid clone?
-                    for loop_ctx in contexts {
+                    for (index0, (loop_ctx, group)) in contexts.iter().enumerate() {
                         let mut merged_ctx = ctx.clone();
-                        merged_ctx.insert(ctx_name.clone(), loop_ctx.clone());
+                        merged_ctx.insert(LOOP_CONTEXT.into(), loop_record(index0, contexts.len()));
+                        if is_value {
+                            merged_ctx.insert_value(ctx_name.clone(), loop_ctx.clone());
+                        } else {
+                            merged_ctx.insert(ctx_name.clone(), loop_ctx.clone());
+                        }
+                        if let Some(records) = group {
+                            merged_ctx.insert_records(ctx_name.clone(), records.clone());
+                        }
+
+                        pop_nodes(&block.nodes, output, record, def, &merged_ctx, template)?;
+                    }
+                    Ok(())
+                }
+                BlockExpr::If(condition) => {
+                    let mut branch_nodes = None;
+
+                    let matches = |condition: &Condition| {
+                        condition
+                            .matches(record, Some(&def.vars), record, &def.defs, ctx)
+                            .with_context(|| template.snippet(&block.location))
+                    };
 
-                        for node in &block.nodes {
-                            node.pop(output, record, def, &merged_ctx)?;
+                    if matches(condition)? {
+                        branch_nodes = Some(&block.nodes);
+                    } else {
+                        for branch in &block.else_branches {
+                            let branch_matches = match &branch.condition {
+                                Some(condition) => matches(condition)?,
+                                None => true,
+                            };
+
+                            if branch_matches {
+                                branch_nodes = Some(&branch.nodes);
+                                break;
+                            }
                         }
                     }
+
+                    if let Some(nodes) = branch_nodes {
+                        pop_nodes(nodes, output, record, def, ctx, template)?;
+                    }
+
                     Ok(())
                 }
-                BlockExpr::If(comparison) => {
-                    if comparison.matches(record, &def.defs, ctx)? {
-                        for node in &block.nodes {
-                            node.pop(output, record, def, ctx)?;
+                BlockExpr::Pop(pop_tag) => {
+                    let mut included_record = record.clone();
+                    let mut included_ctx = ctx.clone();
+
+                    for binding in &pop_tag.with_clause {
+                        let new_field = binding.new_field.clone();
+                        match &binding.value {
+                            // like a value bound by set, a literal is a context with the value as its `$id`
+                            PopValue::Text(text) => {
+                                let context = Record::from([("$id".to_string(), text.clone())]);
+                                included_ctx.insert_value(new_field, context);
+                            }
+                            // renaming a block context makes it available under the new name
+                            PopValue::Expand(expand)
+                                if expand.path.is_empty() && ctx.contains_key(&expand.field) =>
+                            {
+                                included_ctx.rename(&expand.field, new_field);
+                            }
+                            PopValue::Expand(expand) => {
+                                let value = expand
+                                    .run(record, &def.defs, ctx)
+                                    .with_context(|| template.snippet(&block.location))?;
+                                included_record.insert(new_field, value);
+                            }
                         }
                     }
 
+                    pop_nodes(
+                        &block.nodes,
+                        output,
+                        &included_record,
+                        def,
+                        &included_ctx,
+                        template,
+                    )
+                    .with_context(|| format!("In included template `{}`", pop_tag.path))?;
+
                     Ok(())
                 }
+                // set binds for the rest of its enclosing block, so it is handled by pop_nodes
+                BlockExpr::Set(_) => Ok(()),
+                // macros are populated where they are called, calls are compiled to CompiledNode::Call
+                BlockExpr::Macro(_) | BlockExpr::Import(_) | BlockExpr::Call(_) => Ok(()),
+                BlockExpr::With(assignment) => {
+                    let mut with_ctx = ctx.clone();
+                    assignment
+                        .bind(&mut with_ctx, record, def, &template.filters)
+                        .with_context(|| template.snippet(&block.location))?;
+
+                    pop_nodes(&block.nodes, output, record, def, &with_ctx, template)
+                }
             },
+            CompiledNode::Call(call) => {
+                let call_tag = &call.call_tag;
+                // every argument is evaluated before any are bound, so arguments can't see each other's parameters
+                let args = call_tag
+                    .args
+                    .iter()
+                    .map(|arg| arg.evaluate(record, def, ctx, &template.filters))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .with_context(|| template.snippet(&call.location))
+                    .with_context(|| format!("In call to macro `{}`", call_tag.name))?;
+
+                let mut call_ctx = ctx.clone();
+                for (param, (context, records)) in call.params.iter().zip(args) {
+                    call_ctx.bind(param.clone(), context, records);
+                }
+
+                pop_nodes(&call.nodes, output, record, def, &call_ctx, template)
+                    .with_context(|| format!("In call to macro `{}`", call_tag.name))
+            }
             CompiledNode::Text(s) => {
                 output.push_str(s);
                 Ok(())
@@ -154,17 +685,173 @@ impl CompiledNode {
     }
 }
 
-pub type InheritedContext<'a> = HashMap<String, Record>;
+/// Populates `nodes` in order, applying each [`BlockExpr::Set`] to the context of the nodes after it
+fn pop_nodes(
+    nodes: &[CompiledNode],
+    output: &mut String,
+    record: &Record,
+    def: &Definition,
+    ctx: &InheritedContext,
+    template: &Template,
+) -> anyhow::Result<()> {
+    // only clone the context once something is set
+    let mut ctx = Cow::Borrowed(ctx);
+
+    for node in nodes {
+        match node {
+            CompiledNode::Block(CompiledBlock {
+                expr: BlockExpr::Set(assignment),
+                location,
+                ..
+            }) => assignment
+                .bind(ctx.to_mut(), record, def, &template.filters)
+                .with_context(|| template.snippet(location))?,
+            node => node.pop(output, record, def, &ctx, template)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the first newline after each block tag in `nodes`, see [`TemplateOptions::trim_blocks`]
+fn trim_blocks(nodes: &mut [Node]) {
+    for i in 0..nodes.len() {
+        let Node::Block(block) = &mut nodes[i] else {
+            continue;
+        };
+
+        // after the opening tag and each else tag
+        trim_blocks(&mut block.nodes);
+        trim_newline(block.nodes.first_mut());
+        for branch in &mut block.else_branches {
+            trim_blocks(&mut branch.nodes);
+            trim_newline(branch.nodes.first_mut());
+        }
+
+        // after the closing tag, or the only tag of blocks such as set
+        trim_newline(nodes.get_mut(i + 1));
+    }
+}
+
+fn trim_newline(node: Option<&mut Node>) {
+    if let Some(Node::Text(text)) = node {
+        if let Some(rest) = text.strip_prefix('\n').or(text.strip_prefix("\r\n")) {
+            *text = rest.to_string();
+        }
+    }
+}
+
+/// The contexts provided by the blocks surrounding the node being populated, keyed by name
+///
+/// Each context is a [`Record`], e.g. `c` in `{@ for c in country @}`.
+/// The contexts of group by loops also hold the records in their group, see [`ContextIndex::GroupBy`]
+///
+/// Some contexts stand for a single value, their name on its own is their `$id`, see [`InheritedContext::value`].
+/// These are the contexts of value list and group by loops, and those bound by `set`, `with` and macro calls.
+/// The name of any other context on its own is still a field of the record being populated.
+#[derive(Debug, Clone, Default)]
+pub struct InheritedContext {
+    contexts: HashMap<String, Record>,
+    records: HashMap<String, Vec<Record>>,
+    values: HashSet<String>,
+}
+
+impl InheritedContext {
+    pub fn get(&self, name: &str) -> Option<&Record> {
+        self.contexts.get(name)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.contexts.contains_key(name)
+    }
+
+    /// Provides `context` as `name`, hiding any existing context with the same name
+    pub fn insert(&mut self, name: String, context: Record) {
+        self.records.remove(&name);
+        self.values.remove(&name);
+        self.contexts.insert(name, context);
+    }
+
+    /// Like [`insert`](InheritedContext::insert), but `name` on its own is the `$id` of `context`
+    pub fn insert_value(&mut self, name: String, context: Record) {
+        self.insert(name.clone(), context);
+        self.values.insert(name);
+    }
+
+    /// The context named `name`, if it stands for a single value
+    pub fn value(&self, name: &str) -> Option<&Record> {
+        self.contexts
+            .get(name)
+            .filter(|_| self.values.contains(name))
+    }
+
+    /// Provides the records of a group as `name.records`, `name` must already be a context
+    pub fn insert_records(&mut self, name: String, records: Vec<Record>) {
+        self.records.insert(name, records);
+    }
+
+    /// The records of the group named `name`, if it is a group
+    pub fn records(&self, name: &str) -> Option<&[Record]> {
+        self.records.get(name).map(Vec::as_slice)
+    }
+
+    /// Provides `context` as a value named `name`, along with its records if it is a group
+    pub fn bind(&mut self, name: String, context: Record, records: Option<Vec<Record>>) {
+        self.insert_value(name.clone(), context);
+        if let Some(records) = records {
+            self.insert_records(name, records);
+        }
+    }
+
+    /// Provides the context `name`, and its records if it is a group, as `new_name` too
+    pub fn rename(&mut self, name: &str, new_name: String) {
+        let Some(context) = self.contexts.get(name).cloned() else {
+            return;
+        };
+        let records = self.records.get(name).cloned();
+        let is_value = self.values.contains(name);
+
+        self.bind(new_name.clone(), context, records);
+        if !is_value {
+            self.values.remove(&new_name);
+        }
+    }
+}
+
+/// The name of the context holding metadata about the innermost for loop
+pub const LOOP_CONTEXT: &str = "loop";
+
+/// Metadata about the current iteration of a for loop, available inside the loop as the `loop` context:
+///
+/// * `loop.index` counts from 1
+/// * `loop.index0` counts from 0
+/// * `loop.first` is `true` for the first iteration, `false` otherwise
+/// * `loop.last` is `true` for the last iteration, `false` otherwise
+/// * `loop.length` is the total number of iterations
+///
+/// In nested loops `loop` refers to the innermost loop.
+/// `loop` is reserved, [`Template::check`] reports templates that bind it or that look up a field or table named `loop`
+/// inside a loop.
+fn loop_record(index0: usize, length: usize) -> Record {
+    Record::from([
+        ("index".to_string(), (index0 + 1).to_string()),
+        ("index0".to_string(), index0.to_string()),
+        ("first".to_string(), (index0 == 0).to_string()),
+        ("last".to_string(), (index0 + 1 == length).to_string()),
+        ("length".to_string(), length.to_string()),
+    ])
+}
 
 /// [`ContextIndex`] can be used to [`index()`] [`Definition`] to return &[Context] to use while populating a [`Template`]
 ///
 /// [`index()`]: Definition::index
 #[derive(PartialEq, Debug, Clone)]
 pub enum ContextIndex {
-    /// A list of values inline, rather than an Index as such
+    /// A list of values inline, rather than an Index as such.
+    /// Each value is a Record with the value as its `$id`, see [`Value::to_record`](crate::expr::Value::to_record)
     ///
     /// e.g. `{@ for n in [1, 2, 3] @}n is {{n}}{@ end for @}`
-    ValueList(Vec<Value>),
+    ValueList(Vec<Record>),
 
     /// Selects an entire Table to provide as context
     Table { table_name: String },
@@ -172,7 +859,7 @@ pub enum ContextIndex {
     /// A Where Clause selects a filtered list of Records from a Table to provide as context
     FilteredTableWhere {
         table_name: String,
-        where_clause: Comparison,
+        where_clause: Condition,
     },
 
     /// An Other Clause filters out one Record that matches a lookup from the given table Table, and selects all the others to provide as context
@@ -184,7 +871,44 @@ pub enum ContextIndex {
     /// A Where Clause and an Other Clause combined selects all other records that also match the Where Clause
     FilteredTableOtherWhere {
         table_name: String,
-        where_clause: Comparison,
+        where_clause: Condition,
         index: Option<String>,
     },
+
+    /// The records of a group provided by a surrounding group by loop
+    ///
+    /// e.g. `{@ for c in team.records @}` inside `{@ for team in country group by team @}`
+    GroupRecords { context_name: String },
+
+    /// Groups the Records selected by another [`ContextIndex`] by the value of `key`, see [`Definition::groups`]
+    ///
+    /// e.g. `{@ for team in country group by team @}`
+    GroupBy {
+        index: Box<ContextIndex>,
+        key: Expand,
+    },
+
+    /// Sorts and limits the Records selected by another [`ContextIndex`]
+    ///
+    /// e.g. `{@ for c in country where team = "Allies" order by code desc limit 5 @}`
+    Ordered {
+        index: Box<ContextIndex>,
+        order: LoopOrder,
+    },
+}
+
+impl ContextIndex {
+    /// The name of the table the selected records are from, `None` for values and the records of a group
+    pub fn table_name(&self) -> Option<&str> {
+        match self {
+            ContextIndex::Table { table_name }
+            | ContextIndex::FilteredTableWhere { table_name, .. }
+            | ContextIndex::FilteredTableOther { table_name, .. }
+            | ContextIndex::FilteredTableOtherWhere { table_name, .. } => Some(table_name),
+            ContextIndex::GroupBy { index, .. } | ContextIndex::Ordered { index, .. } => {
+                index.table_name()
+            }
+            ContextIndex::ValueList(_) | ContextIndex::GroupRecords { .. } => None,
+        }
+    }
 }
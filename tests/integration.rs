@@ -1,5 +1,6 @@
 mod cases;
 use cases::{read_test_case, run_test_cases};
+use popvars::Definition;
 
 #[test]
 fn for_loop_test_cases() -> Result<(), Box<dyn std::error::Error>> {
@@ -11,9 +12,575 @@ fn if_test_cases() -> Result<(), Box<dyn std::error::Error>> {
     Ok(run_test_cases("if")?)
 }
 
+#[test]
+fn filter_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("filter")?)
+}
+
+#[test]
+fn aggregate_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("aggregate")?)
+}
+
+#[test]
+fn aggregate_error_names_the_record() -> Result<(), Box<dyn std::error::Error>> {
+    let country = indoc::indoc! {"
+        $id,code
+        Germany,45
+        France,forty
+    "};
+    let defs = [(String::from("country"), country.to_string())];
+    let definition = Definition::from_csv_strings("team\nAllies\n".to_string(), defs.iter())?;
+
+    let Err(err) = popvars::pop("{{sum(country.code)}}", definition) else {
+        panic!("expected a sum over text to fail");
+    };
+    assert!(
+        format!("{err:#}").contains("record `France` of `country`"),
+        "unexpected error: {err:#}"
+    );
+
+    // records without a `$id` are named by their row in the file, not their place among the records summed
+    let score = indoc::indoc! {"
+        team,points
+        Allies,1
+        Axis,2
+        Allies,many
+    "};
+    let defs = [(String::from("score"), score.to_string())];
+    let definition = Definition::from_csv_strings("team\nAllies\n".to_string(), defs.iter())?;
+
+    let Err(err) = popvars::pop("{{sum(score.points where team = \"Allies\")}}", definition) else {
+        panic!("expected a sum over text to fail");
+    };
+    assert!(
+        format!("{err:#}").contains("Expected `many` to be a number in row 4 of `score`"),
+        "unexpected error: {err:#}"
+    );
+
+    let Err(err) = popvars::Template::compile("{{sum(country)}}") else {
+        panic!("expected a sum without a field to fail to compile");
+    };
+    assert!(
+        err.to_string().contains("`sum` needs a field"),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn set_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("set")?)
+}
+
+#[test]
+fn macro_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("macro")?)
+}
+
+#[test]
+fn macro_must_be_defined_with_matching_arguments() {
+    let Err(err) = popvars::Template::compile("{@ call shout(country) @}") else {
+        panic!("expected an undefined macro to fail to compile");
+    };
+    assert!(
+        err.to_string().contains("Unknown macro `shout`"),
+        "unexpected error: {err:#}"
+    );
+
+    let Err(err) = popvars::Template::compile(
+        "{@ macro shout(text) @}{{text}}{@ end macro @}{@ call shout(country, city) @}",
+    ) else {
+        panic!("expected a call with too many arguments to fail to compile");
+    };
+    assert!(
+        err.to_string()
+            .contains("takes 1 arguments but was called with 2"),
+        "unexpected error: {err:#}"
+    );
+}
+
+#[test]
+fn macro_defined_in_a_block_is_only_callable_inside_it() {
+    let inside = popvars::Template::compile(
+        "{@ if country = \"France\" @}{@ macro shout(text) @}{{text}}!{@ end macro @}{@ call shout(country) @}{@ end if @}",
+    );
+    assert!(
+        inside.is_ok(),
+        "unexpected error: {:#}",
+        inside.unwrap_err()
+    );
+
+    let Err(err) = popvars::Template::compile(
+        "{@ if country = \"France\" @}{@ macro shout(text) @}{{text}}!{@ end macro @}{@ end if @}{@ call shout(country) @}",
+    ) else {
+        panic!("expected a call to a macro defined in an if block to fail to compile");
+    };
+    assert!(
+        format!("{err:#}").contains("Unknown macro `shout`"),
+        "unexpected error: {err:#}"
+    );
+}
+
+#[test]
+fn comment_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("comment")?)
+}
+
+#[test]
+fn raw_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("raw")?)
+}
+
+#[test]
+fn custom_delimiters() -> Result<(), Box<dyn std::error::Error>> {
+    let syntax = popvars::TemplateSyntax {
+        expr_open: "<<".into(),
+        expr_close: ">>".into(),
+        block_open: "<%".into(),
+        block_close: "%>".into(),
+        comment_open: "<#".into(),
+        comment_close: "#>".into(),
+    };
+    let options = popvars::TemplateOptions {
+        syntax,
+        ..Default::default()
+    };
+    let template = r#"<# {# isn't a comment #><% if country = "Germany"%>{ tag = <<country.code>> }<% end if %> {{country}} {# \<<"#;
+
+    let country = "$id,code\nGermany,45\n".to_string();
+    let defs = [(String::from("country"), country)];
+    let definition = Definition::from_csv_strings("country\nGermany\n".to_string(), defs.iter())?;
+
+    let popped = popvars::pop_with_options(template, definition, options)?;
+    assert_eq!(popped, vec![String::from("{ tag = 45 } {{country}} {# <<")]);
+
+    let options = popvars::TemplateOptions {
+        syntax: popvars::TemplateSyntax {
+            block_open: "{{".into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert!(popvars::Template::compile_with_options("{{country}}", options).is_err());
+    Ok(())
+}
+
+#[test]
+fn whitespace_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("whitespace")?)
+}
+
+#[test]
+fn trim_blocks() -> Result<(), Box<dyn std::error::Error>> {
+    let template = indoc::indoc! {"
+        {@ for c in country @}
+        {@ if c.team = \"Allies\" @}
+        {{c.$id}}
+        {@ else @}
+        {{c.$id}} is not an ally
+        {@ end if @}
+        {@ end for @}
+        done
+    "};
+    let country = indoc::indoc! {"
+        $id,team
+        Germany,Allies
+        Soviet Union,Soviets
+    "};
+    let defs = [(String::from("country"), country.to_string())];
+    let definition = Definition::from_csv_strings("team\nAllies\n".to_string(), defs.iter())?;
+
+    let options = popvars::TemplateOptions {
+        trim_blocks: true,
+        ..Default::default()
+    };
+    let popped = popvars::pop_with_options(template, definition, options)?;
+    assert_eq!(
+        popped,
+        vec![String::from("Germany\nSoviet Union is not an ally\ndone\n")]
+    );
+    Ok(())
+}
+
+#[test]
+fn types_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("types")?)
+}
+
+#[test]
+fn validate_reports_every_problem() -> Result<(), Box<dyn std::error::Error>> {
+    let vars = indoc::indoc! {"
+        country,city
+        Germany,Berlin
+        Frnace,Paris
+    "};
+    let country = indoc::indoc! {"
+        $id,code
+        Germany,45
+        France,40
+        Germany,46
+        ,47
+    "};
+    let city = "name\nBerlin\n".to_string();
+    let defs = [
+        (String::from("country"), country.to_string()),
+        (String::from("city"), city),
+    ];
+    let definition = Definition::from_csv_strings(vars.to_string(), defs.iter())?;
+
+    let Err(err) = definition.validate() else {
+        panic!("expected the definitions to be invalid");
+    };
+    let err = err.to_string();
+    for problem in [
+        "Found 4 problems",
+        "`city` has no `$id` field",
+        "`country` row 4 has the same `$id` `Germany` as row 2",
+        "`country` row 5 has an empty `$id`",
+        "`vars` row 3 field `country`: `Frnace` is not the `$id` of any record in `country`",
+    ] {
+        assert!(err.contains(problem), "missing `{problem}` in: {err}");
+    }
+
+    let country = "$id,code\nGermany,45\n".to_string();
+    let defs = [(String::from("country"), country)];
+    let definition = Definition::from_csv_strings("country\nGermany\n".to_string(), defs.iter())?;
+    definition.validate()?;
+    Ok(())
+}
+
+#[test]
+fn validate_numbers_rows_as_in_the_sheet() -> Result<(), Box<dyn std::error::Error>> {
+    // both sheets have empty rows between their records
+    let definition = Definition::from_workbook("tests/cases/workbook/empty-rows.xlsx")?;
+
+    let Err(err) = definition.validate() else {
+        panic!("expected the definitions to be invalid");
+    };
+    let err = err.to_string();
+    for problem in [
+        "Found 2 problems",
+        "`country` row 5 has the same `$id` `France` as row 2",
+        "`vars` row 4 field `country`: `Frnace` is not the `$id` of any record in `country`",
+    ] {
+        assert!(err.contains(problem), "missing `{problem}` in: {err}");
+    }
+    Ok(())
+}
+
+#[test]
+fn type_errors_number_cells_as_in_the_sheet() {
+    // both sheets start at C2
+    let Err(err) = Definition::from_workbook("tests/cases/workbook/offset.xlsx") else {
+        panic!("expected `forty` not to be an int");
+    };
+    assert!(
+        format!("{err:#}").contains("row 3 column 4"),
+        "unexpected error: {err:#}"
+    );
+}
+
+#[test]
+fn check_reports_every_problem() -> Result<(), Box<dyn std::error::Error>> {
+    let country = indoc::indoc! {"
+        $id,code,team
+        Germany,45,Allies
+        France,40,Allies
+    "};
+    let defs = [(String::from("country"), country.to_string())];
+    let definition = Definition::from_csv_strings("country\nGermany\n".to_string(), defs.iter())?;
+
+    let template = popvars::Template::compile(indoc::indoc! {"
+        {{country.cod}}
+        {@ for c in contry @}{{c}}{@ end for @}
+        {@ for c in country where team = \"Allies\" @}
+          {{c.code}} {{loop.index}} {{c.team.name}}
+        {@ end for @}
+        {{c.code}} {{country@Enemy.code}}
+    "})?;
+
+    let Err(err) = template.check(&definition) else {
+        panic!("expected the template to have problems");
+    };
+    let err = err.to_string();
+    for problem in [
+        "Found 5 problems",
+        "line 1 column 1: `country` has no field `cod`",
+        "line 2 column 1: There is no table named `contry`",
+        "line 4 column 29: There is no table named `team`",
+        "line 6 column 1: `c` is the context of a loop and can only be used inside that loop",
+        "line 6 column 12: `vars` has no field `Enemy` to look up `country` with",
+    ] {
+        assert!(err.contains(problem), "missing `{problem}` in: {err}");
+    }
+
+    let template = popvars::Template::compile(
+        "{@ for c in country @}{{c.code}} {{loop.first}}{@ end for @} {{country.team}}",
+    )?;
+    template.check(&definition)?;
+
+    // the context of a loop over a table is a record, so its name on its own is a field of vars
+    let template = popvars::Template::compile("{@ for c in country @}{{c}}{@ end for @}")?;
+    let Err(err) = template.check(&definition) else {
+        panic!("expected a record context on its own to be a problem");
+    };
+    assert!(
+        err.to_string().contains(
+            "`c` is a record, so on its own it is a field of `vars`, use `c.$id` for its `$id`"
+        ),
+        "unexpected error: {err}"
+    );
+    Ok(())
+}
+
+#[test]
+fn loop_is_a_reserved_name() -> Result<(), Box<dyn std::error::Error>> {
+    let defs = [
+        (
+            String::from("country"),
+            "$id,code\nGermany,45\n".to_string(),
+        ),
+        (String::from("loop"), "$id,name\nfirst,First\n".to_string()),
+    ];
+    let template = popvars::Template::compile(
+        "{@ for c in country @}{{country@loop.code}} {{loop.name}}{@ end for @}{@ set loop = \"x\" @}",
+    )?;
+
+    // a lookup with `loop` inside a loop finds the metadata instead of the field or table named `loop`
+    let definition =
+        Definition::from_csv_strings("country,loop\nGermany,first\n".to_string(), defs.iter())?;
+    let Err(err) = template.check(&definition) else {
+        panic!("expected `loop` to be a problem");
+    };
+    let err = err.to_string();
+    for problem in [
+        "line 1 column 23: Inside a for loop `loop` is the metadata of the loop, which hides the field `loop` of `vars`",
+        "line 1 column 71: `loop` is reserved for the metadata of for loops, use another name",
+    ] {
+        assert!(err.contains(problem), "missing `{problem}` in: {err}");
+    }
+
+    let definition = Definition::from_csv_strings("country\nGermany\n".to_string(), defs.iter())?;
+    let Err(err) = template.check(&definition) else {
+        panic!("expected `loop` to be a problem");
+    };
+    let err = err.to_string();
+    let problem =
+        "line 1 column 45: Inside a for loop `loop` is the metadata of the loop, which hides the table `loop`";
+    assert!(err.contains(problem), "missing `{problem}` in: {err}");
+    Ok(())
+}
+
+#[test]
+fn errors_show_where_they_are_in_the_template() -> Result<(), Box<dyn std::error::Error>> {
+    let country = "$id,code\nGermany,45\n".to_string();
+    let defs = [(String::from("country"), country)];
+    let definition = Definition::from_csv_strings("country\nGermany\n".to_string(), defs.iter())?;
+
+    let options = popvars::TemplateOptions {
+        name: Some("greeting.txt".into()),
+        ..Default::default()
+    };
+    let template = "Hello\n  {{country.cod}}!";
+    let Err(err) = popvars::pop_with_options(template, definition, options) else {
+        panic!("expected a missing field to fail");
+    };
+    assert!(
+        format!("{err:#}").contains("row 2 of vars"),
+        "unexpected error: {err:#}"
+    );
+    let expected = indoc::indoc! {"
+        At `greeting.txt` line 2 column 3:
+          |
+        2 |   {{country.cod}}!
+          |   ^^^^^^^^^^^^^^^"};
+    assert!(
+        format!("{err:#}").contains(expected),
+        "unexpected error: {err:#}"
+    );
+
+    let Err(err) = popvars::Template::compile("ok\n{@ for c in country @}{{c}}") else {
+        panic!("expected a block without an end to fail to parse");
+    };
+    let expected = indoc::indoc! {"
+        At line 2 column 1:
+          |
+        2 | {@ for c in country @}{{c}}
+          | ^^^^^^^^^^^^^^^^^^^^^^"};
+    assert!(
+        err.to_string().contains(expected),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn bad_records_in_loops_are_errors() -> Result<(), Box<dyn std::error::Error>> {
+    let country = indoc::indoc! {"
+        $id,code
+        Germany,45
+        France,forty
+    "};
+    let defs = [(String::from("country"), country.to_string())];
+    let definition = Definition::from_csv_strings("country\nGermany\n".to_string(), defs.iter())?;
+
+    let template = "{@ for c in country where code > 40 @}{{c.$id}}{@ end for @}";
+    let Err(err) = popvars::pop(template, definition) else {
+        panic!("expected a where clause comparing text with a number to fail");
+    };
+    assert!(
+        format!("{err:#}").contains("where clause `code > 40` for row 3 of `country`"),
+        "unexpected error: {err:#}"
+    );
+
+    let country = indoc::indoc! {"
+        name,code
+        Germany,45
+    "};
+    let defs = [(String::from("country"), country.to_string())];
+    let definition = Definition::from_csv_strings("country\nGermany\n".to_string(), defs.iter())?;
+
+    let template = "{@ for other c in country @}{{c.code}}{@ end for @}";
+    let Err(err) = popvars::pop(template, definition) else {
+        panic!("expected an other loop over records without a `$id` to fail");
+    };
+    assert!(
+        format!("{err:#}").contains("Row 2 of `country` has no `$id`"),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn pop_test_cases() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(run_test_cases("pop")?)
+}
+
+#[test]
+fn pop_cycle_is_an_error() {
+    let Err(err) = popvars::Template::compile("{@ pop tests/includes/cycle-a.txt @}") else {
+        panic!("expected include cycle to fail to compile");
+    };
+
+    assert!(
+        format!("{err:#}").contains("Include cycle detected"),
+        "unexpected error: {err:#}"
+    );
+}
+
+#[test]
+fn templates_compare_by_what_they_compiled() -> Result<(), Box<dyn std::error::Error>> {
+    let template = popvars::Template::compile("{{country}} fights for the {{team}}")?;
+    assert_eq!(
+        template,
+        popvars::Template::compile("{{country}} fights for the {{team}}")?
+    );
+    assert_ne!(
+        template,
+        popvars::Template::compile("{{country}} fights for {{team}}")?
+    );
+    Ok(())
+}
+
 #[test]
 fn simple() -> Result<(), Box<dyn std::error::Error>> {
     let test_case = read_test_case("simple.md")?;
     test_case.run()?;
     Ok(())
 }
+
+#[test]
+fn ods_workbook() -> Result<(), Box<dyn std::error::Error>> {
+    let definition = Definition::from_ods_file("tests/cases/workbook/sc-mod.ods")?;
+    assert_eq!(definition.defs.len(), 1);
+
+    let popped = popvars::pop("{{country}} ({{country.code}}) holds {{city}}.", definition)?;
+    assert_eq!(
+        popped,
+        vec![
+            String::from("Germany (45) holds Konigsberg."),
+            String::from("France (40) holds Marseille."),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn auto_detected_workbook() -> Result<(), Box<dyn std::error::Error>> {
+    let definition = Definition::from_workbook("tests/cases/workbook/founded.xlsx")?;
+
+    let popped = popvars::pop("{{country}} {{founded}} {{population}}", definition)?;
+    assert_eq!(
+        popped,
+        vec![
+            String::from("France 1950-05-01 67.5"),
+            String::from("UK 1960-04-28 67"),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn outfiles_group_rows_in_order() -> Result<(), Box<dyn std::error::Error>> {
+    let vars = indoc::indoc! {"
+        $outfile,country
+        allies.txt,France
+        ,Spain
+        axis.txt,Germany
+        allies.txt,UK
+        ./axis.txt,Italy
+        other/../allies.txt,USA
+    "};
+    let definition = Definition::from_csv_strings(vars.to_string(), [].iter())?;
+
+    let outfiles = popvars::pop_outfiles("{{country}}", definition)?;
+    assert_eq!(
+        outfiles,
+        vec![
+            popvars::Outfile {
+                path: Some("allies.txt".into()),
+                rows: vec![
+                    String::from("France"),
+                    String::from("UK"),
+                    String::from("USA"),
+                ],
+            },
+            popvars::Outfile {
+                path: None,
+                rows: vec![String::from("Spain")],
+            },
+            popvars::Outfile {
+                path: Some("axis.txt".into()),
+                rows: vec![String::from("Germany"), String::from("Italy")],
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn custom_filters() -> Result<(), Box<dyn std::error::Error>> {
+    let mut filters = popvars::Filters::new();
+    filters.register("game_id", |country, _| {
+        Ok(country.to_lowercase().replace(' ', "_"))
+    });
+
+    let Err(err) = popvars::Template::compile("{{country | game_id}}") else {
+        panic!("expected an unregistered filter to fail to compile");
+    };
+    assert!(
+        err.to_string().contains("Unknown filter `game_id`"),
+        "{err}"
+    );
+
+    let template =
+        popvars::Template::compile_with_filters("{{country | game_id | upper}}", filters)?;
+
+    let vars = "country\nSoviet Union\n".to_string();
+    let definition = Definition::from_csv_strings(vars, [].iter())?;
+
+    let popped = template.pop(&definition.vars.records[0], &definition)?;
+    assert_eq!(popped, "SOVIET_UNION");
+    Ok(())
+}